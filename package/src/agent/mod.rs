@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::budget::ClientIdentity;
+use crate::chat_completion::{chat_tools_completion_endpoint, count_messages_tokens, Message, ToolDefinition};
+use crate::embedding::Usage;
+use crate::models::{ModelEndpoint, MODEL_REGISTRY};
+use crate::routing::BudgetExceeded;
+use crate::service::{check_budget, update_rate_limit};
+use crate::tokenizer::{count_tokens, truncate_to_tokens};
+
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> String;
+
+    fn description(&self) -> String;
+
+    // the JSON schema of the arguments object
+    fn parameters(&self) -> serde_json::Value;
+
+    // the output is fed back to the model as it is
+    async fn call(&self, arguments: serde_json::Value) -> anyhow::Result<String>;
+}
+
+type ToolFunction = Box<dyn Fn(serde_json::Value) -> anyhow::Result<String> + Send + Sync>;
+
+// a tool from a synchronous closure
+pub struct FunctionTool {
+    name: String,
+    description: String,
+    parameters: serde_json::Value,
+    function: ToolFunction,
+}
+
+impl FunctionTool {
+    pub fn new<F>(name: &str, description: &str, parameters: serde_json::Value, function: F) -> Self
+    where F: Fn(serde_json::Value) -> anyhow::Result<String> + Send + Sync + 'static {
+        FunctionTool { name: name.to_string(), description: description.to_string(), parameters, function: Box::new(function) }
+    }
+}
+
+#[async_trait]
+impl Tool for FunctionTool {
+    fn name(&self) -> String {
+        self.name.to_owned()
+    }
+
+    fn description(&self) -> String {
+        self.description.to_owned()
+    }
+
+    fn parameters(&self) -> serde_json::Value {
+        self.parameters.clone()
+    }
+
+    async fn call(&self, arguments: serde_json::Value) -> anyhow::Result<String> {
+        (self.function)(arguments)
+    }
+}
+
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: BTreeMap<String, Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        ToolRegistry::default()
+    }
+
+    // replaces a tool of the same name
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.insert(tool.name(), tool);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.tools.keys().map(|x| x.as_str()).collect()
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools.values().map(|x| ToolDefinition { name: x.name(), description: x.description(), parameters: x.parameters() }).collect()
+    }
+
+    // the arguments are parsed and checked for the schema's required fields before the tool runs
+    pub async fn call(&self, name: &str, arguments: &str) -> anyhow::Result<String> {
+        let tool = self.tools.get(name).ok_or_else(|| anyhow::anyhow!("Error: unknown tool '{}'", name))?;
+        let arguments = if arguments.trim().is_empty() { serde_json::json!({}) } else { serde_json::from_str::<serde_json::Value>(arguments)? };
+        if !arguments.is_object() {
+            return Err(anyhow::anyhow!("Error: the arguments of tool '{}' are not a JSON object", name));
+        }
+        let parameters = tool.parameters();
+        for key in parameters.get("required").and_then(|x| x.as_array()).into_iter().flatten().filter_map(|x| x.as_str()) {
+            if arguments.get(key).is_none() {
+                return Err(anyhow::anyhow!("Error: tool '{}' called without the required argument '{}'", name, key));
+            }
+        }
+        tool.call(arguments).await
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentConfig {
+    pub model_name: String,
+    pub system: String,
+    pub completion_token_limit: u16,
+    // completions, including the one with the final answer
+    pub max_steps: usize,
+    // in dollars, a step that could exceed it is not started
+    pub max_costs: Option<f64>,
+    // longer tool outputs are truncated before they are fed back
+    pub tool_output_tokens: usize,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        AgentConfig {
+            model_name: "gpt-4".to_string(),
+            system: "You are Cosmos Rust Bot. Use the tools when they help to answer. Attributes: helpful, expert, truthful.".to_string(),
+            completion_token_limit: 500,
+            max_steps: 8,
+            max_costs: None,
+            tool_output_tokens: 2_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AgentStop {
+    FinalAnswer,
+    MaxSteps,
+    BudgetExhausted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCallTrace {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+    pub output: String,
+    pub error: bool,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentStep {
+    pub content: Option<String>,
+    pub tool_calls: Vec<ToolCallTrace>,
+    pub usage: Usage,
+    pub costs: f64,
+    // of the completion, the tool calls are timed separately
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AgentTrace {
+    pub answer: Option<String>,
+    pub stop: AgentStop,
+    pub steps: Vec<AgentStep>,
+    pub costs: f64,
+    pub messages: Vec<Message>,
+}
+
+pub struct Agent {
+    config: AgentConfig,
+    tools: ToolRegistry,
+}
+
+impl Agent {
+    pub fn new(config: AgentConfig, tools: ToolRegistry) -> Self {
+        Agent { config, tools }
+    }
+
+    pub fn config(&self) -> &AgentConfig {
+        &self.config
+    }
+
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    // calls the model until it answers without calling tools, tool errors are fed back so the model can recover,
+    // every step is paid from the client's budget and stops the run once the budget can not cover it
+    pub async fn run(&self, client: &ClientIdentity, task: &str) -> anyhow::Result<AgentTrace> {
+        let model = MODEL_REGISTRY.validate(&self.config.model_name, ModelEndpoint::Chat)?;
+        if !model.supports_tools {
+            return Err(anyhow::anyhow!("Error: model '{}' does not support tools", model.name));
+        }
+        let definitions = self.tools.definitions();
+        let mut messages = vec![Message::new("system", &self.config.system), Message::new("user", task)];
+        let mut steps: Vec<AgentStep> = Vec::new();
+        let mut costs = 0.0;
+
+        let stop = loop {
+            if steps.len() >= self.config.max_steps {
+                break AgentStop::MaxSteps;
+            }
+            let prompt_tokens = count_messages_tokens(&model.name, &messages) + count_tokens(&model.name, &serde_json::json!(definitions).to_string());
+            if let Some(max_costs) = self.config.max_costs {
+                if costs + model.costs(prompt_tokens as u64, self.config.completion_token_limit as u64) > max_costs {
+                    break AgentStop::BudgetExhausted;
+                }
+            }
+            if let Err(err) = check_budget(client, &model, prompt_tokens, self.config.completion_token_limit) {
+                match err.downcast_ref::<BudgetExceeded>() {
+                    Some(_) => break AgentStop::BudgetExhausted,
+                    None => return Err(err),
+                }
+            }
+
+            let started = Instant::now();
+            let completion = chat_tools_completion_endpoint(&model.name, &messages, &definitions, self.config.completion_token_limit).await?;
+            let duration_ms = started.elapsed().as_millis() as u64;
+            update_rate_limit(client, &model, &completion.usage)?;
+            let message = completion.choices.first().map(|x| x.message.clone()).ok_or_else(|| anyhow::anyhow!("Error: ChatCompletion empty!"))?;
+            let step_costs = model.usage_costs(&completion.usage);
+            costs += step_costs;
+            messages.push(message.clone());
+
+            let mut tool_calls = Vec::new();
+            for call in message.tool_calls.iter().flatten() {
+                let started = Instant::now();
+                let (output, error) = match self.tools.call(&call.function.name, &call.function.arguments).await {
+                    Ok(output) => (truncate_to_tokens(&model.name, &output, self.config.tool_output_tokens), false),
+                    Err(err) => (format!("Error: {}", err), true),
+                };
+                messages.push(Message::tool_result(&call.id, &output));
+                tool_calls.push(ToolCallTrace {
+                    id: call.id.to_owned(),
+                    name: call.function.name.to_owned(),
+                    arguments: call.function.arguments.to_owned(),
+                    output,
+                    error,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                });
+            }
+            let final_answer = tool_calls.is_empty();
+            steps.push(AgentStep { content: message.content, tool_calls, usage: completion.usage, costs: step_costs, duration_ms });
+            if final_answer {
+                break AgentStop::FinalAnswer;
+            }
+        };
+
+        let answer = match stop {
+            AgentStop::FinalAnswer => steps.last().and_then(|x| x.content.to_owned()),
+            _ => None,
+        };
+        Ok(AgentTrace { answer, stop, steps, costs, messages })
+    }
+}
@@ -0,0 +1,94 @@
+use reqwest::Client;
+use reqwest::header::HeaderValue;
+use reqwest::header::CONTENT_TYPE;
+use serde::de::DeserializeOwned;
+
+use crate::embedding::Usage;
+use crate::throttle;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+    pub error_type: Option<String>,
+    pub code: Option<String>,
+}
+
+impl ApiError {
+    fn from_response(status: u16, body: &str) -> Self {
+        let error = serde_json::from_str::<serde_json::Value>(body).ok()
+            .and_then(|x| x.get("error").cloned())
+            .unwrap_or(serde_json::Value::Null);
+        ApiError {
+            status,
+            message: error.get("message").and_then(|x| x.as_str()).map(|x| x.to_string()).unwrap_or(body.to_string()),
+            error_type: error.get("type").and_then(|x| x.as_str()).map(|x| x.to_string()),
+            code: error.get("code").and_then(|x| x.as_str()).map(|x| x.to_string()),
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.status == 429
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error: OpenAI API returned {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// a request that failed after some of its calls were billed, the usage still has to be charged
+#[derive(Debug)]
+pub struct PartiallyBilled {
+    pub usages: Vec<Usage>,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for PartiallyBilled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for PartiallyBilled {}
+
+impl PartiallyBilled {
+    // the error as it is if nothing was billed
+    pub fn wrap(usages: Vec<Usage>, error: anyhow::Error) -> anyhow::Error {
+        if usages.is_empty() {
+            error
+        } else {
+            PartiallyBilled { usages, error }.into()
+        }
+    }
+}
+
+// rough estimate when no tokenizer is available, about four characters per token
+pub fn estimate_tokens(text: &str) -> u64 {
+    (text.len() as u64).div_ceil(4)
+}
+
+pub async fn post_json<T: DeserializeOwned>(url: &str, model_name: &str, estimated_tokens: u64, json_data: &serde_json::Value) -> anyhow::Result<T> {
+
+    throttle::acquire(model_name, estimated_tokens).await;
+
+    let client = Client::new();
+    let response = client.post(url)
+        .bearer_auth(&super::ENV.openai_api_key)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(json_data.to_string())
+        .send().await?;
+
+    let status = response.status().as_u16();
+    throttle::observe_response(model_name, status, response.headers());
+
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(ApiError::from_response(status, &body).into());
+    }
+
+    Ok(response.json::<T>().await?)
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+pub const BUDGET_CONFIG_PATH: &str = "./tmp/rust_openai_gpt_tools_budgets.json";
+
+pub const ANONYMOUS_CLIENT_ID: &str = "anonymous";
+
+#[derive(Debug)]
+pub struct BudgetAccount {
+    name: String,
+    max_costs: f64,
+    duration: Duration,
+    remaining_budget: f64,
+    last_check: Instant,
+}
+
+impl BudgetAccount {
+    pub fn new(name: &str, max_costs: f64, duration: Duration) -> Self {
+        BudgetAccount {
+            name: name.to_string(),
+            max_costs,
+            duration,
+            remaining_budget: max_costs,
+            last_check: Instant::now(),
+        }
+    }
+
+    fn from_limit(name: &str, limit: &BudgetLimit) -> Self {
+        BudgetAccount::new(name, limit.max_costs, Duration::from_secs(limit.duration_secs))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn max_costs(&self) -> f64 {
+        self.max_costs
+    }
+
+    pub fn remaining_budget(&self) -> f64 {
+        self.remaining_budget
+    }
+
+    pub fn rate_limit(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.last_check) > self.duration {
+            self.remaining_budget = self.max_costs;
+            self.last_check = now;
+        }
+        self.remaining_budget > 0.0
+    }
+
+    pub fn update_rate_limit(&mut self, costs: f64) {
+        self.remaining_budget -= costs;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BudgetLimit {
+    pub max_costs: f64,
+    pub duration_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClientBudgetConfig {
+    pub project: Option<String>,
+    pub limit: Option<BudgetLimit>,
+    // the peer uids allowed to act as this client
+    #[serde(default)]
+    pub uids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BudgetConfig {
+    pub global: BudgetLimit,
+    #[serde(default)]
+    pub projects: HashMap<String, BudgetLimit>,
+    #[serde(default)]
+    pub clients: HashMap<String, ClientBudgetConfig>,
+    // applied to every client that has no limit of its own
+    #[serde(default)]
+    pub default_client_limit: Option<BudgetLimit>,
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        BudgetConfig {
+            // 25$ my upper price limit
+            global: BudgetLimit { max_costs: 25.0, duration_secs: 60 * 60 * 24 * 30 },
+            projects: HashMap::new(),
+            clients: HashMap::new(),
+            default_client_limit: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ClientIdentity {
+    pub client_id: String,
+    pub project: Option<String>,
+    // checked against the socket's peer credentials, per-client policies only apply to verified identities
+    #[serde(default)]
+    pub verified: bool,
+}
+
+impl ClientIdentity {
+    pub fn anonymous() -> Self {
+        ClientIdentity { client_id: ANONYMOUS_CLIENT_ID.to_string(), project: None, verified: false }
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetAccounts {
+    config: BudgetConfig,
+    global: BudgetAccount,
+    projects: HashMap<String, BudgetAccount>,
+    clients: HashMap<String, BudgetAccount>,
+}
+
+impl BudgetAccounts {
+    pub fn new(config: BudgetConfig) -> Self {
+        let global = BudgetAccount::from_limit("global", &config.global);
+        let projects = config.projects.iter()
+            .map(|(name, limit)| (name.to_owned(), BudgetAccount::from_limit(&format!("project:{}", name), limit)))
+            .collect();
+        BudgetAccounts {
+            config,
+            global,
+            projects,
+            clients: HashMap::new(),
+        }
+    }
+
+    // the peer's uid is the identity, a claimed client id is only accepted if the config maps it to that uid,
+    // and a claimed project only if it is the client's configured one
+    pub fn verify_identity(&self, uid: Option<u32>, claimed: Option<(String, Option<String>)>) -> anyhow::Result<ClientIdentity> {
+        let uid = match (uid, claimed.as_ref()) {
+            (Some(uid), _) => uid,
+            (None, None) => return Ok(ClientIdentity::anonymous()),
+            (None, Some((client_id, _))) => return Err(anyhow::anyhow!("Error: client '{}' claimed without peer credentials", client_id)),
+        };
+        let (client_id, project) = match claimed {
+            Some((client_id, project)) => {
+                if !self.config.clients.get(&client_id).map(|x| x.uids.contains(&uid)).unwrap_or(false) {
+                    return Err(anyhow::anyhow!("Error: client '{}' is not mapped to uid {}", client_id, uid));
+                }
+                (client_id, project)
+            }
+            None => (format!("uid:{}", uid), None),
+        };
+        let configured_project = self.config.clients.get(&client_id).and_then(|x| x.project.to_owned());
+        if let Some(project) = project {
+            if configured_project.as_ref() != Some(&project) {
+                return Err(anyhow::anyhow!("Error: project '{}' is not configured for client '{}'", project, client_id));
+            }
+        }
+        Ok(ClientIdentity { client_id, project: configured_project, verified: true })
+    }
+
+    fn client_account(&mut self, client_id: &str) -> Option<&mut BudgetAccount> {
+        if !self.clients.contains_key(client_id) {
+            let limit = self.config.clients.get(client_id)
+                .and_then(|x| x.limit.to_owned())
+                .or_else(|| self.config.default_client_limit.to_owned())?;
+            self.clients.insert(client_id.to_string(), BudgetAccount::from_limit(&format!("client:{}", client_id), &limit));
+        }
+        self.clients.get_mut(client_id)
+    }
+
+    fn accounts(&mut self, identity: &ClientIdentity) -> Vec<&mut BudgetAccount> {
+        // make sure the client account exists before borrowing the others
+        self.client_account(&identity.client_id);
+
+        let mut accounts = Vec::new();
+        if let Some(account) = self.clients.get_mut(&identity.client_id) {
+            accounts.push(account);
+        }
+        if let Some(account) = identity.project.as_ref().and_then(|x| self.projects.get_mut(x)) {
+            accounts.push(account);
+        }
+        accounts.push(&mut self.global);
+        accounts
+    }
+
+    pub fn rate_limit(&mut self, identity: &ClientIdentity) -> anyhow::Result<()> {
+        for account in self.accounts(identity) {
+            if !account.rate_limit() {
+                println!("{:?}", account);
+                return Err(anyhow::anyhow!("Error: Rate Exceeded! (budget '{}' exhausted)", account.name()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update_rate_limit(&mut self, identity: &ClientIdentity, costs: f64) {
+        for account in self.accounts(identity) {
+            account.update_rate_limit(costs);
+            println!("{:?}", account);
+        }
+    }
+
+    // the lowest share of budget left across the hierarchy, between 0.0 and 1.0
+    pub fn remaining_budget_fraction(&mut self, identity: &ClientIdentity) -> f64 {
+        self.accounts(identity).iter()
+            .map(|x| if x.max_costs() > 0.0 { (x.remaining_budget() / x.max_costs()).clamp(0.0, 1.0) } else { 0.0 })
+            .fold(1.0, f64::min)
+    }
+
+    pub fn remaining_budget(&mut self, identity: &ClientIdentity) -> f64 {
+        self.accounts(identity).iter()
+            .map(|x| x.remaining_budget())
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+pub fn load_budget_config(path: &str) -> BudgetConfig {
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<BudgetConfig>(&json) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Error: invalid budget config at '{}': {}, using defaults", path, err);
+                BudgetConfig::default()
+            }
+        },
+        Err(_) => BudgetConfig::default(),
+    }
+}
@@ -1,5 +1,33 @@
 use serde::{Deserialize, Serialize};
 use sled::IVec;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use lazy_static::lazy_static;
+
+pub const SLED_DB_PATH: &str = "./tmp/rust_openai_gpt_tools_sled_db";
+
+lazy_static!{
+   // sled can only be opened once per process, all stores are trees of this database
+   pub static ref SLED_DB: sled::Db = load_sled_db(SLED_DB_PATH);
+}
+
+pub fn load_sled_db(path: &str) -> sled::Db {
+    sled::Config::default()
+        .path(path)
+        .cache_capacity( 1024 * 1024 * 1024) // 1gb
+        //.use_compression(true)
+        //.compression_factor(22)
+        .flush_every_ms(Some(100))
+        .open()
+        .unwrap()
+}
+
+pub fn digest<T: Hash>(item: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
 
 
 pub struct HashValueStore(SledStore);
@@ -11,6 +39,10 @@ impl HashValueStore {
         HashValueStore(sled_store)
     }
 
+    pub fn open_tree(db: &sled::Db, name: &str) -> anyhow::Result<Self> {
+        Ok(HashValueStore(SledStore::from_tree(db.open_tree(name)?)))
+    }
+
     pub fn contains_hash(&self, hash: u64) -> anyhow::Result<bool> {
         self.0.contains_key(hash.to_be_bytes().to_vec())
     }
@@ -38,13 +70,19 @@ impl HashValueStore {
 }
 
 pub struct SledStore {
-    db: sled::Db,
+    db: sled::Tree,
 }
 
 impl SledStore {
     pub fn new(sled_db: sled::Db) -> Self {
         SledStore {
-            db: sled_db,
+            db: (*sled_db).clone(),
+        }
+    }
+
+    pub fn from_tree(tree: sled::Tree) -> Self {
+        SledStore {
+            db: tree,
         }
     }
 
@@ -59,7 +97,7 @@ impl SledStore {
         where
             K: AsRef<Vec<u8>>,
     {
-        Ok(self.db.get(key.as_ref())?)
+        self.db.get(key.as_ref())
     }
 
     fn insert<K, V>(&self, key: K, value: V) -> anyhow::Result<()>
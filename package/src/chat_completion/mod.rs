@@ -1,7 +1,7 @@
 
-use reqwest::Client;
-use reqwest::header::HeaderValue;
-use reqwest::header::CONTENT_TYPE;
+use crate::api::post_json;
+use crate::tokenizer::{count_chat_tokens, count_tokens, clamp_completion_tokens};
+use crate::prompt::default_stop_tokens;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct ChatCompletion {
@@ -17,38 +17,148 @@ pub struct Choice {
     index: i64,
     pub message: Message,
     finish_reason: String,
+    // only if requested
+    #[serde(default)]
+    pub logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ChoiceLogprobs {
+    pub content: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Message {
     pub role: String,
-    pub content: String,
+    // None if the assistant only calls tools
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    // the call a "tool" message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: &str, content: &str) -> Self {
+        Message { role: role.to_string(), content: Some(content.to_string()), tool_calls: None, tool_call_id: None }
+    }
+
+    pub fn tool_result(tool_call_id: &str, content: &str) -> Self {
+        Message { role: "tool".to_string(), content: Some(content.to_string()), tool_calls: None, tool_call_id: Some(tool_call_id.to_string()) }
+    }
+
+    pub fn content(&self) -> &str {
+        self.content.as_deref().unwrap_or("")
+    }
+
+    // the content and the tool calls, as far as they count towards the prompt
+    pub fn text(&self) -> String {
+        let mut text = self.content().to_string();
+        for call in self.tool_calls.iter().flatten() {
+            text.push_str(&format!("{}({})", call.function.name, call.function.arguments));
+        }
+        text
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    // a JSON object as a string, not necessarily valid
+    pub arguments: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ToolDefinition {
+    pub name: String,
+    pub description: String,
+    // the JSON schema of the arguments object
+    pub parameters: serde_json::Value,
+}
+
+pub fn count_messages_tokens(model_name: &str, messages: &[Message]) -> usize {
+    let texts = messages.iter().map(|x| (x.role.to_owned(), x.text())).collect::<Vec<(String, String)>>();
+    count_chat_tokens(model_name, &texts.iter().map(|(role, text)| (role.as_str(), text.as_str())).collect::<Vec<(&str, &str)>>())
 }
 
 pub async fn chat_completion_endpoint(model_name: &str, system: &str, prompt: &str, completion_token_limit: u16) -> anyhow::Result<ChatCompletion> {
+    chat_completion_endpoint_with_stop(model_name, system, prompt, completion_token_limit, &default_stop_tokens()).await
+}
+
+pub async fn chat_completion_endpoint_with_stop(model_name: &str, system: &str, prompt: &str, completion_token_limit: u16, stop: &[String]) -> anyhow::Result<ChatCompletion> {
+    chat_messages_completion_endpoint(model_name, &[Message::new("system", system), Message::new("user", prompt)], completion_token_limit, stop, false).await
+}
+
+// `logprobs` asks for the log probability of every output token and its 5 likeliest alternatives
+pub async fn chat_messages_completion_endpoint(model_name: &str, messages: &[Message], completion_token_limit: u16, stop: &[String], logprobs: bool) -> anyhow::Result<ChatCompletion> {
+    let mut options = serde_json::json!({});
+    // OpenAI rejects an empty list of stop sequences
+    if !stop.is_empty() {
+        options["stop"] = serde_json::json!(stop);
+    }
+    if logprobs {
+        options["logprobs"] = serde_json::json!(true);
+        options["top_logprobs"] = serde_json::json!(5);
+    }
+    chat_completion_request(model_name, messages, count_messages_tokens(model_name, messages), completion_token_limit, options).await
+}
+
+// the model decides whether to call tools or answer
+pub async fn chat_tools_completion_endpoint(model_name: &str, messages: &[Message], tools: &[ToolDefinition], completion_token_limit: u16) -> anyhow::Result<ChatCompletion> {
+    let mut options = serde_json::json!({});
+    if !tools.is_empty() {
+        options["tools"] = serde_json::json!(tools.iter().map(|x| serde_json::json!({"type": "function", "function": x})).collect::<Vec<serde_json::Value>>());
+        options["tool_choice"] = serde_json::json!("auto");
+    }
+    let prompt_tokens = count_messages_tokens(model_name, messages) + count_tokens(model_name, &options.to_string());
+    chat_completion_request(model_name, messages, prompt_tokens, completion_token_limit, options).await
+}
 
-    let json_data = serde_json::json!({
+async fn chat_completion_request(model_name: &str, messages: &[Message], prompt_tokens: usize, completion_token_limit: u16, options: serde_json::Value) -> anyhow::Result<ChatCompletion> {
+
+    let max_tokens = clamp_completion_tokens(model_name, prompt_tokens, completion_token_limit)?;
+
+    let mut json_data = serde_json::json!({
                 "model": model_name, // "gpt-3.5-turbo", "gpt-4"
-                "messages": [{"role": "system", "content": system},{"role": "user", "content": prompt}],
-                "max_tokens": if completion_token_limit > super::MAX_TOKENS { super::MAX_TOKENS }else{ completion_token_limit },
+                "messages": messages,
+                "max_tokens": max_tokens,
                 "temperature": 0,
                 "presence_penalty": 1.0,
                 "frequency_penalty": 1.0,
                 "top_p": 1,
                 "n": 1,
-                "stop": ["<result","<result>","</result>"]
               });
+    if let serde_json::Value::Object(options) = options {
+        for (key, value) in options {
+            json_data[key] = value;
+        }
+    }
 
-    let client = Client::new();
-    let url = "https://api.openai.com/v1/chat/completions";
-    let response = client.post(url)
-        .bearer_auth(&super::ENV.openai_api_key)
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(json_data.to_string())
-        .send().await;
-
-    let completion = response?.json::<ChatCompletion>().await?;
+    let completion = post_json::<ChatCompletion>("https://api.openai.com/v1/chat/completions", model_name, (prompt_tokens + max_tokens as usize) as u64, &json_data).await?;
 
     Ok(completion)
 }
-
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::api::PartiallyBilled;
+use crate::cache::SLED_DB;
+use crate::chat_completion::{chat_completion_endpoint, chat_messages_completion_endpoint, count_messages_tokens, Message};
+use crate::embedding::Usage;
+use crate::prompt::escape;
+use crate::tokenizer::context_window;
+
+pub const CONVERSATION_CONFIG_PATH: &str = "./tmp/rust_openai_gpt_tools_conversation.json";
+
+lazy_static!{
+   pub static ref CONVERSATION_CONFIG: ConversationConfig = load_conversation_config(CONVERSATION_CONFIG_PATH);
+   static ref SESSION_LOCKS: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+// held while a session is read, answered and saved, so concurrent messages of one session do not lose turns
+pub struct SessionLock {
+    session_id: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+pub async fn lock_session(session_id: &str) -> SessionLock {
+    let lock = match SESSION_LOCKS.lock() {
+        Ok(mut locks) => locks.entry(session_id.to_string()).or_default().clone(),
+        Err(_) => Arc::default(),
+    };
+    let guard = lock.clone().lock_owned().await;
+    SessionLock { session_id: session_id.to_string(), lock, guard: Some(guard) }
+}
+
+impl Drop for SessionLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        // the map holds one reference and this lock another, nobody else is waiting
+        if let Ok(mut locks) = SESSION_LOCKS.lock() {
+            if Arc::strong_count(&self.lock) == 2 {
+                locks.remove(&self.session_id);
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConversationConfig {
+    pub model_name: String,
+    pub system: String,
+    pub completion_token_limit: u16,
+    // the history is kept below this many tokens, None uses the model's context window minus the completion limit
+    #[serde(default)]
+    pub context_tokens: Option<usize>,
+    // older turns are summarized instead of dropped
+    pub summarize: bool,
+    // the most recent turns are never summarized or dropped, unless they alone do not fit
+    pub keep_recent_turns: usize,
+    pub summary_token_limit: u16,
+}
+
+impl Default for ConversationConfig {
+    fn default() -> Self {
+        ConversationConfig {
+            model_name: "gpt-3.5-turbo".to_string(),
+            system: "You are Cosmos Rust Bot. Attributes: helpful, expert, truthful.".to_string(),
+            completion_token_limit: 500,
+            context_tokens: None,
+            summarize: true,
+            keep_recent_turns: 4,
+            summary_token_limit: 300,
+        }
+    }
+}
+
+pub fn load_conversation_config(path: &str) -> ConversationConfig {
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<ConversationConfig>(&json) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Error: invalid conversation config at '{}': {}, using defaults", path, err);
+                ConversationConfig::default()
+            }
+        },
+        Err(_) => ConversationConfig::default(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Turn {
+    // "user" or "assistant"
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ConversationState {
+    // of the turns that no longer fit
+    pub summary: Option<String>,
+    pub turns: Vec<Turn>,
+    // summarized or dropped so far
+    pub trimmed_turns: usize,
+}
+
+// the history of one session, stored in the "conversations" tree under its session id
+pub struct Conversation {
+    session_id: String,
+    state: ConversationState,
+    config: ConversationConfig,
+    tree: sled::Tree,
+}
+
+impl Conversation {
+    pub fn open(session_id: &str, config: ConversationConfig) -> anyhow::Result<Self> {
+        let tree = SLED_DB.open_tree("conversations")?;
+        let state = match tree.get(session_id.as_bytes())? {
+            Some(bytes) => bincode::deserialize::<ConversationState>(&bytes)?,
+            None => ConversationState::default(),
+        };
+        Ok(Conversation { session_id: session_id.to_string(), state, config, tree })
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn state(&self) -> &ConversationState {
+        &self.state
+    }
+
+    pub fn config(&self) -> &ConversationConfig {
+        &self.config
+    }
+
+    pub fn push(&mut self, role: &str, content: &str) {
+        self.state.turns.push(Turn { role: role.to_string(), content: content.to_string() });
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.tree.insert(self.session_id.as_bytes(), bincode::serialize(&self.state)?)?;
+        Ok(())
+    }
+
+    // removes the latest answer and the message it answers, e.g. after the answer was rejected
+    pub fn discard_exchange(&mut self) {
+        if self.state.turns.last().map(|x| x.role == "assistant").unwrap_or(false) {
+            self.state.turns.pop();
+        }
+        if self.state.turns.last().map(|x| x.role == "user").unwrap_or(false) {
+            self.state.turns.pop();
+        }
+    }
+
+    // forgets the session, the conversation starts over
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.state = ConversationState::default();
+        self.tree.remove(self.session_id.as_bytes())?;
+        Ok(())
+    }
+
+    fn system(&self) -> String {
+        match &self.state.summary {
+            Some(summary) => format!("{}\n<summary description='the earlier conversation'>{}</summary>", self.config.system, escape(summary)),
+            None => self.config.system.to_owned(),
+        }
+    }
+
+    // the system message with the summary, followed by the turns
+    pub fn messages(&self) -> Vec<Message> {
+        let mut messages = vec![Message::new("system", &self.system())];
+        messages.extend(self.state.turns.iter().map(|x| Message::new(&x.role, &x.content)));
+        messages
+    }
+
+    pub fn tokens(&self) -> usize {
+        count_messages_tokens(&self.config.model_name, &self.messages())
+    }
+
+    pub fn context_tokens(&self) -> usize {
+        self.config.context_tokens.unwrap_or_else(|| context_window(&self.config.model_name).saturating_sub(self.config.completion_token_limit as usize))
+    }
+
+    async fn summarize(&self, turns: &[Turn]) -> anyhow::Result<(String, Usage)> {
+        let mut source = self.state.summary.as_ref().map(|x| format!("{}\n", x)).unwrap_or_default();
+        for turn in turns {
+            source.push_str(&format!("{}: {}\n", turn.role, turn.content));
+        }
+        let prompt = format!("<instruction>Summarize the following conversation, keep the facts and the open questions</instruction><source>{}</source>\n\n<result>", escape(&source));
+        let completion = chat_completion_endpoint(&self.config.model_name, &self.config.system, &prompt, self.config.summary_token_limit).await?;
+        let summary = completion.choices.first().map(|x| x.message.content().trim().to_string())
+            .ok_or_else(|| anyhow::anyhow!("Error: ChatCompletion empty!"))?;
+        Ok((summary, completion.usage))
+    }
+
+    // summarizes or drops the oldest turns until the history fits, returns the usage of the summaries,
+    // on failure the summaries so far are kept and the error is PartiallyBilled with their usage
+    pub async fn fit(&mut self) -> anyhow::Result<Vec<Usage>> {
+        let mut usages = Vec::new();
+        let context_tokens = self.context_tokens();
+        while self.tokens() > context_tokens && !self.state.turns.is_empty() {
+            let older = self.state.turns.len().saturating_sub(self.config.keep_recent_turns);
+            // at least one turn goes, the latest is kept so there is something to answer
+            let count = older.max(1).min(self.state.turns.len() - 1);
+            if count == 0 {
+                let error = anyhow::anyhow!("Error: the latest message of session '{}' alone exceeds {} tokens", self.session_id, context_tokens);
+                return Err(PartiallyBilled::wrap(usages, error));
+            }
+            let trimmed = self.state.turns.drain(..count).collect::<Vec<Turn>>();
+            if self.config.summarize {
+                let (summary, usage) = match self.summarize(&trimmed).await {
+                    Ok(o) => o,
+                    Err(error) => {
+                        // the turns that were not summarized stay in the history
+                        self.state.turns.splice(0..0, trimmed);
+                        return Err(PartiallyBilled::wrap(usages, error));
+                    }
+                };
+                self.state.summary = Some(summary);
+                usages.push(usage);
+            }
+            self.state.trimmed_turns += count;
+        }
+        Ok(usages)
+    }
+
+    // answers the history as it is and adds the answer without saving
+    pub async fn answer(&mut self) -> anyhow::Result<(String, Usage)> {
+        let completion = chat_messages_completion_endpoint(&self.config.model_name, &self.messages(), self.config.completion_token_limit, &[], false).await?;
+        let answer = completion.choices.first().map(|x| x.message.content().to_string())
+            .ok_or_else(|| anyhow::anyhow!("Error: ChatCompletion empty!"))?;
+        self.push("assistant", &answer);
+        Ok((answer, completion.usage))
+    }
+
+    // adds the message and the answer without saving, returns the answer and the usage of every completion
+    pub async fn reply(&mut self, message: &str) -> anyhow::Result<(String, Vec<Usage>)> {
+        self.push("user", message);
+        let mut usages = self.fit().await?;
+        let (answer, usage) = self.answer().await?;
+        usages.push(usage);
+        Ok((answer, usages))
+    }
+
+    pub async fn send(&mut self, message: &str) -> anyhow::Result<String> {
+        let (answer, _) = self.reply(message).await?;
+        self.save()?;
+        Ok(answer)
+    }
+}
@@ -0,0 +1,305 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::index::{cosine_similarity, dot, normalize as normalize_vector};
+
+pub fn normalize(vectors: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    vectors.iter().map(|x| normalize_vector(x)).collect()
+}
+
+// cosine similarity of every pair, symmetric with ones on the diagonal (zero for null vectors)
+pub fn similarity_matrix(vectors: &[Vec<f32>]) -> Vec<Vec<f32>> {
+    let normalized = normalize(vectors);
+    let mut matrix = vec![vec![0.0; vectors.len()]; vectors.len()];
+    for i in 0..normalized.len() {
+        for j in i..normalized.len() {
+            let similarity = dot(&normalized[i], &normalized[j]);
+            matrix[i][j] = similarity;
+            matrix[j][i] = similarity;
+        }
+    }
+    matrix
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KMeans {
+    // the cluster of each vector
+    pub assignments: Vec<usize>,
+    // normalized, clusters are compared by cosine similarity
+    pub centroids: Vec<Vec<f32>>,
+    pub iterations: usize,
+}
+
+impl KMeans {
+    // the indices of the vectors in each cluster
+    pub fn clusters(&self) -> Vec<Vec<usize>> {
+        let mut clusters = vec![Vec::new(); self.centroids.len()];
+        for (i, cluster) in self.assignments.iter().enumerate() {
+            clusters[*cluster].push(i);
+        }
+        clusters
+    }
+}
+
+fn centroid(vectors: &[Vec<f32>], members: &[usize]) -> Vec<f32> {
+    let mut sum = vec![0.0; vectors.first().map(|x| x.len()).unwrap_or(0)];
+    for i in members {
+        for (s, x) in sum.iter_mut().zip(vectors[*i].iter()) {
+            *s += x;
+        }
+    }
+    normalize(&[sum]).remove(0)
+}
+
+fn nearest(vector: &[f32], centroids: &[Vec<f32>]) -> (usize, f32) {
+    centroids.iter().enumerate()
+        .map(|(i, x)| (i, dot(vector, x)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+// spherical k-means seeded with k-means++, stops when no vector changes its cluster,
+// every vector is assigned at least once, even with max_iterations 0
+pub fn kmeans(vectors: &[Vec<f32>], k: usize, max_iterations: usize) -> KMeans {
+    let max_iterations = max_iterations.max(1);
+    let vectors = normalize(vectors);
+    let k = k.min(vectors.len());
+    if k == 0 {
+        return KMeans { assignments: vec![0; vectors.len()], centroids: Vec::new(), iterations: 0 };
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut centroids = vec![vectors[rng.gen_range(0..vectors.len())].clone()];
+    while centroids.len() < k {
+        // the further from the chosen centroids, the likelier a vector becomes the next one
+        let distances = vectors.iter().map(|x| (1.0 - nearest(x, &centroids).1).max(0.0).powi(2)).collect::<Vec<f32>>();
+        let total: f32 = distances.iter().sum();
+        let next = if total > 0.0 {
+            let mut target = rng.gen_range(0.0..total);
+            distances.iter().position(|x| { target -= x; target <= 0.0 }).unwrap_or(vectors.len() - 1)
+        } else {
+            rng.gen_range(0..vectors.len())
+        };
+        centroids.push(vectors[next].clone());
+    }
+
+    let mut assignments = vec![usize::MAX; vectors.len()];
+    let mut iterations = 0;
+    while iterations < max_iterations {
+        iterations += 1;
+        let mut changed = false;
+        for (i, vector) in vectors.iter().enumerate() {
+            let (cluster, _) = nearest(vector, &centroids);
+            if assignments[i] != cluster {
+                assignments[i] = cluster;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        let fit = vectors.iter().zip(assignments.iter()).map(|(x, cluster)| dot(x, &centroids[*cluster])).collect::<Vec<f32>>();
+        let mut taken = Vec::new();
+        for (cluster, centroid_vector) in centroids.iter_mut().enumerate() {
+            let members = (0..vectors.len()).filter(|i| assignments[*i] == cluster).collect::<Vec<usize>>();
+            *centroid_vector = if members.is_empty() {
+                // an empty cluster takes over the vector that fits its own cluster worst
+                let worst = (0..vectors.len())
+                    .filter(|i| !taken.contains(i))
+                    .min_by(|a, b| fit[*a].total_cmp(&fit[*b]))
+                    .unwrap_or(0);
+                taken.push(worst);
+                vectors[worst].clone()
+            } else {
+                centroid(&vectors, &members)
+            };
+        }
+    }
+    KMeans { assignments, centroids, iterations }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    // the most similar pair of members
+    Single,
+    // the least similar pair of members
+    Complete,
+    // the mean similarity of all pairs
+    Average,
+}
+
+// merges the most similar clusters until no pair is at least `threshold` similar
+pub fn agglomerative_clustering(vectors: &[Vec<f32>], threshold: f32, linkage: Linkage) -> Vec<Vec<usize>> {
+    let mut similarities = similarity_matrix(vectors);
+    let mut clusters: Vec<Option<Vec<usize>>> = (0..vectors.len()).map(|i| Some(vec![i])).collect();
+    loop {
+        let mut best: Option<(usize, usize, f32)> = None;
+        for i in 0..clusters.len() {
+            if clusters[i].is_none() {
+                continue;
+            }
+            for j in i + 1..clusters.len() {
+                if clusters[j].is_some() && best.map(|x| similarities[i][j] > x.2).unwrap_or(true) {
+                    best = Some((i, j, similarities[i][j]));
+                }
+            }
+        }
+        let (i, j) = match best {
+            Some((i, j, similarity)) if similarity >= threshold => (i, j),
+            _ => break,
+        };
+        let size_i = clusters[i].as_ref().map(|x| x.len()).unwrap_or(0) as f32;
+        let size_j = clusters[j].as_ref().map(|x| x.len()).unwrap_or(0) as f32;
+        // Lance-Williams update of the merged cluster's similarities
+        for other in 0..clusters.len() {
+            if other == i || other == j || clusters[other].is_none() {
+                continue;
+            }
+            let (a, b) = (similarities[i][other], similarities[j][other]);
+            let merged = match linkage {
+                Linkage::Single => a.max(b),
+                Linkage::Complete => a.min(b),
+                Linkage::Average => (a * size_i + b * size_j) / (size_i + size_j),
+            };
+            similarities[i][other] = merged;
+            similarities[other][i] = merged;
+        }
+        let mut members = clusters[j].take().unwrap_or_default();
+        if let Some(cluster) = clusters[i].as_mut() {
+            cluster.append(&mut members);
+            cluster.sort_unstable();
+        }
+    }
+    clusters.into_iter().flatten().collect()
+}
+
+// groups of at least two vectors connected by pairs at least `threshold` similar
+pub fn near_duplicates(vectors: &[Vec<f32>], threshold: f32) -> Vec<Vec<usize>> {
+    let normalized = normalize(vectors);
+    let mut parents = (0..vectors.len()).collect::<Vec<usize>>();
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    for i in 0..normalized.len() {
+        for j in i + 1..normalized.len() {
+            if dot(&normalized[i], &normalized[j]) >= threshold {
+                let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                if a != b {
+                    parents[b.max(a)] = a.min(b);
+                }
+            }
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root = vec![usize::MAX; vectors.len()];
+    for i in 0..vectors.len() {
+        let r = root(&mut parents, i);
+        if group_of_root[r] == usize::MAX {
+            group_of_root[r] = groups.len();
+            groups.push(Vec::new());
+        }
+        groups[group_of_root[r]].push(i);
+    }
+    groups.into_iter().filter(|x| x.len() > 1).collect()
+}
+
+// picks k candidates relevant to the query but unlike each other,
+// lambda 1.0 ranks by relevance only, 0.0 by diversity only
+pub fn maximal_marginal_relevance(query: &[f32], candidates: &[Vec<f32>], k: usize, lambda: f32) -> Vec<usize> {
+    let relevance = candidates.iter().map(|x| cosine_similarity(query, x)).collect::<Vec<f32>>();
+    let normalized = normalize(candidates);
+    let mut selected: Vec<usize> = Vec::new();
+    while selected.len() < k.min(candidates.len()) {
+        let next = (0..candidates.len())
+            .filter(|i| !selected.contains(i))
+            .map(|i| {
+                let redundancy = selected.iter().map(|j| dot(&normalized[i], &normalized[*j])).fold(f32::MIN, f32::max);
+                let redundancy = if selected.is_empty() { 0.0 } else { redundancy };
+                (i, lambda * relevance[i] - (1.0 - lambda) * redundancy)
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|x| x.0);
+        match next {
+            Some(i) => selected.push(i),
+            None => break,
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a unit vector in the plane, `degrees` from the x axis
+    fn at(degrees: f32) -> Vec<f32> {
+        let radians = degrees.to_radians();
+        vec![radians.cos(), radians.sin()]
+    }
+
+    #[test]
+    fn similarity_matrix_is_symmetric() {
+        let matrix = similarity_matrix(&[at(0.0), at(60.0), vec![0.0, 0.0]]);
+        assert!((matrix[0][0] - 1.0).abs() < 1e-6);
+        assert!((matrix[0][1] - 0.5).abs() < 1e-6);
+        assert_eq!(matrix[0][1], matrix[1][0]);
+        assert_eq!(matrix[2][2], 0.0);
+    }
+
+    #[test]
+    fn kmeans_separates_distinct_directions() {
+        let vectors = [0.0, 120.0, 240.0].iter()
+            .flat_map(|x| [-0.6, -0.2, 0.2, 0.6].map(|jitter| at(x + jitter)))
+            .collect::<Vec<Vec<f32>>>();
+        let result = kmeans(&vectors, 3, 50);
+        assert_eq!(result.centroids.len(), 3);
+        let mut clusters = result.clusters();
+        clusters.sort();
+        assert_eq!(clusters, vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9, 10, 11]]);
+        for centroid in result.centroids.iter() {
+            assert!((dot(centroid, centroid) - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn kmeans_clamps_k() {
+        let result = kmeans(&[at(0.0), at(90.0)], 5, 10);
+        assert_eq!(result.centroids.len(), 2);
+        assert_ne!(result.assignments[0], result.assignments[1]);
+        assert!(kmeans(&[at(0.0)], 0, 10).centroids.is_empty());
+        assert!(kmeans(&[], 3, 10).assignments.is_empty());
+        let result = kmeans(&[at(0.0), at(90.0)], 2, 0);
+        assert_eq!(result.iterations, 1);
+        assert_eq!(result.clusters().iter().map(|x| x.len()).sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn agglomerative_clustering_depends_on_linkage() {
+        // 0-1 and 1-2 are close, 0-2 are not
+        let vectors = vec![at(0.0), at(20.0), at(42.0), at(180.0)];
+        assert_eq!(agglomerative_clustering(&vectors, 0.8, Linkage::Single), vec![vec![0, 1, 2], vec![3]]);
+        assert_eq!(agglomerative_clustering(&vectors, 0.8, Linkage::Average), vec![vec![0, 1, 2], vec![3]]);
+        assert_eq!(agglomerative_clustering(&vectors, 0.8, Linkage::Complete), vec![vec![0, 1], vec![2], vec![3]]);
+        assert_eq!(agglomerative_clustering(&vectors, 0.9, Linkage::Average), vec![vec![0, 1], vec![2], vec![3]]);
+        assert_eq!(agglomerative_clustering(&vectors, 1.1, Linkage::Single).len(), 4);
+    }
+
+    #[test]
+    fn near_duplicates_are_grouped_transitively() {
+        let vectors = vec![at(0.0), at(20.0), at(42.0), at(90.0), at(180.0), at(181.0)];
+        assert_eq!(near_duplicates(&vectors, 0.9), vec![vec![0, 1, 2], vec![4, 5]]);
+        assert!(near_duplicates(&vectors, 1.1).is_empty());
+    }
+
+    #[test]
+    fn maximal_marginal_relevance_trades_relevance_for_diversity() {
+        let candidates = vec![at(18.0), at(20.0), at(-30.0)];
+        assert_eq!(maximal_marginal_relevance(&at(0.0), &candidates, 2, 1.0), vec![0, 1]);
+        assert_eq!(maximal_marginal_relevance(&at(0.0), &candidates, 2, 0.5), vec![0, 2]);
+        assert_eq!(maximal_marginal_relevance(&at(0.0), &candidates, 5, 0.5).len(), 3);
+    }
+}
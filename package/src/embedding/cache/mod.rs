@@ -0,0 +1,243 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{digest, SLED_DB};
+
+pub const EMBEDDING_CACHE_CONFIG_PATH: &str = "./tmp/rust_openai_gpt_tools_embedding_cache.json";
+
+lazy_static!{
+   pub static ref EMBEDDING_CACHE: EmbeddingCache = EmbeddingCache::open(&SLED_DB, load_embedding_cache_config(EMBEDDING_CACHE_CONFIG_PATH).quantization).unwrap();
+}
+
+// how vectors are stored, f16 halves and i8 quarters the size at a small loss of precision
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmbeddingQuantization {
+    F32,
+    F16,
+    I8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddingCacheConfig {
+    pub quantization: EmbeddingQuantization,
+}
+
+impl Default for EmbeddingCacheConfig {
+    fn default() -> Self {
+        EmbeddingCacheConfig { quantization: EmbeddingQuantization::F32 }
+    }
+}
+
+pub fn load_embedding_cache_config(path: &str) -> EmbeddingCacheConfig {
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<EmbeddingCacheConfig>(&json) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Error: invalid embedding cache config at '{}': {}, using defaults", path, err);
+                EmbeddingCacheConfig::default()
+            }
+        },
+        Err(_) => EmbeddingCacheConfig::default(),
+    }
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let round = (mantissa >> (shift - 1)) & 1;
+        return sign | ((mantissa >> shift) + round) as u16;
+    }
+    let half = sign | ((exponent as u16) << 10) | (mantissa >> 13) as u16;
+    // a carry into the exponent is still the correctly rounded value
+    if mantissa & 0x1000 != 0 { half + 1 } else { half }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    let bits = if exponent == 0 {
+        if mantissa == 0 {
+            sign
+        } else {
+            let mut exponent = 127 - 15 + 1;
+            let mut mantissa = mantissa;
+            while mantissa & 0x400 == 0 {
+                mantissa <<= 1;
+                exponent -= 1;
+            }
+            sign | (exponent << 23) | ((mantissa & 0x3ff) << 13)
+        }
+    } else if exponent == 0x1f {
+        sign | 0x7f80_0000 | (mantissa << 13)
+    } else {
+        sign | ((exponent + 127 - 15) << 23) | (mantissa << 13)
+    };
+    f32::from_bits(bits)
+}
+
+// the first byte tells the quantization, so entries written with another setting stay readable
+pub fn encode_vector(vector: &[f32], quantization: EmbeddingQuantization) -> Vec<u8> {
+    match quantization {
+        EmbeddingQuantization::F32 => {
+            let mut bytes = Vec::with_capacity(1 + vector.len() * 4);
+            bytes.push(0);
+            vector.iter().for_each(|x| bytes.extend_from_slice(&x.to_le_bytes()));
+            bytes
+        }
+        EmbeddingQuantization::F16 => {
+            let mut bytes = Vec::with_capacity(1 + vector.len() * 2);
+            bytes.push(1);
+            vector.iter().for_each(|x| bytes.extend_from_slice(&f32_to_f16(*x).to_le_bytes()));
+            bytes
+        }
+        EmbeddingQuantization::I8 => {
+            // symmetric, the largest magnitude maps to 127
+            let scale = vector.iter().fold(0.0f32, |a, x| a.max(x.abs())) / 127.0;
+            let mut bytes = Vec::with_capacity(5 + vector.len());
+            bytes.push(2);
+            bytes.extend_from_slice(&scale.to_le_bytes());
+            vector.iter().for_each(|x| bytes.push(if scale > 0.0 { (x / scale).round().clamp(-127.0, 127.0) as i8 as u8 } else { 0 }));
+            bytes
+        }
+    }
+}
+
+pub fn decode_vector(bytes: &[u8]) -> anyhow::Result<Vec<f32>> {
+    let (tag, data) = bytes.split_first().ok_or_else(|| anyhow::anyhow!("Error: empty cached embedding"))?;
+    match tag {
+        0 if data.len() % 4 == 0 => Ok(data.chunks_exact(4).map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])).collect()),
+        1 if data.len() % 2 == 0 => Ok(data.chunks_exact(2).map(|x| f16_to_f32(u16::from_le_bytes([x[0], x[1]]))).collect()),
+        2 if data.len() >= 4 => {
+            let scale = f32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            Ok(data[4..].iter().map(|x| *x as i8 as f32 * scale).collect())
+        }
+        _ => Err(anyhow::anyhow!("Error: invalid cached embedding")),
+    }
+}
+
+// embeddings by model and text, in their own tree of the sled database
+pub struct EmbeddingCache {
+    tree: sled::Tree,
+    quantization: EmbeddingQuantization,
+}
+
+impl EmbeddingCache {
+    pub fn open(db: &sled::Db, quantization: EmbeddingQuantization) -> anyhow::Result<Self> {
+        Ok(EmbeddingCache { tree: db.open_tree("embedding_cache")?, quantization })
+    }
+
+    pub fn quantization(&self) -> EmbeddingQuantization {
+        self.quantization
+    }
+
+    // "<model>\0" followed by the digest of the text, so the entries of a model can be scanned by prefix
+    fn key(model_name: &str, text: &str) -> Vec<u8> {
+        let mut key = Self::prefix(model_name);
+        key.extend_from_slice(&digest(&text).to_be_bytes());
+        key
+    }
+
+    fn prefix(model_name: &str) -> Vec<u8> {
+        let mut prefix = model_name.as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+
+    pub fn get(&self, model_name: &str, text: &str) -> anyhow::Result<Option<Vec<f32>>> {
+        match self.tree.get(Self::key(model_name, text))? {
+            Some(value) => Ok(Some(decode_vector(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, model_name: &str, text: &str, vector: &[f32]) -> anyhow::Result<()> {
+        self.tree.insert(Self::key(model_name, text), encode_vector(vector, self.quantization))?;
+        Ok(())
+    }
+
+    pub fn len(&self, model_name: &str) -> usize {
+        self.tree.scan_prefix(Self::prefix(model_name)).count()
+    }
+
+    pub fn is_empty(&self, model_name: &str) -> bool {
+        self.len(model_name) == 0
+    }
+
+    // drops the embeddings of a model, returns how many were removed
+    pub fn clear(&self, model_name: &str) -> anyhow::Result<usize> {
+        let mut removed = 0;
+        for item in self.tree.scan_prefix(Self::prefix(model_name)) {
+            let (key, _) = item?;
+            self.tree.remove(key)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f16_conversion_matches_ieee_half_precision() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(0.5), 0x3800);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(0.1), 0x2e66);
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        assert_eq!(f32_to_f16(-0.0), 0x8000);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        // subnormal halves
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x03ff), 1023.0 * 2f32.powi(-24));
+    }
+
+    #[test]
+    fn vectors_round_trip_every_quantization() {
+        let vector = vec![0.0, 1.0, -0.5, 0.123_456, -0.031_25, 0.000_1];
+        assert_eq!(decode_vector(&encode_vector(&vector, EmbeddingQuantization::F32)).unwrap(), vector);
+
+        let f16 = decode_vector(&encode_vector(&vector, EmbeddingQuantization::F16)).unwrap();
+        for (a, b) in vector.iter().zip(f16.iter()) {
+            assert!((a - b).abs() <= a.abs() / 1024.0 + 1e-7, "{} {}", a, b);
+        }
+
+        let encoded = encode_vector(&vector, EmbeddingQuantization::I8);
+        assert_eq!(encoded.len(), 5 + vector.len());
+        let i8 = decode_vector(&encoded).unwrap();
+        assert!((i8[1] - 1.0).abs() < 1e-6);
+        for (a, b) in vector.iter().zip(i8.iter()) {
+            assert!((a - b).abs() <= 0.5 / 127.0 + 1e-6, "{} {}", a, b);
+        }
+        assert_eq!(decode_vector(&encode_vector(&[0.0, 0.0], EmbeddingQuantization::I8)).unwrap(), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn invalid_entries_are_rejected() {
+        assert!(decode_vector(&[]).is_err());
+        assert!(decode_vector(&[0, 1, 2, 3]).is_err());
+        assert!(decode_vector(&[1, 1]).is_err());
+        assert!(decode_vector(&[2, 0, 0]).is_err());
+        assert!(decode_vector(&[3, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,589 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::SLED_DB;
+
+// caps the random level of HNSW nodes, 2^16 times the neighbours per layer is plenty
+const MAX_HNSW_LEVEL: usize = 16;
+// removed slots that are tolerated on top of one per live node before the index is compacted
+const MIN_COMPACTION_SLOTS: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SimilarityMetric {
+    // vectors are normalized on insert, scores are between -1.0 and 1.0
+    Cosine,
+    DotProduct,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswConfig {
+    // neighbours per node and layer, twice as many on the bottom layer
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        HnswConfig { m: 16, ef_construction: 200, ef_search: 64 }
+    }
+}
+
+impl HnswConfig {
+    fn max_neighbours(&self, layer: usize) -> usize {
+        if layer == 0 { self.m * 2 } else { self.m }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmbeddingIndexConfig {
+    pub metric: SimilarityMetric,
+    // None searches by brute force, exact but linear in the size of the collection
+    pub hnsw: Option<HnswConfig>,
+}
+
+impl Default for EmbeddingIndexConfig {
+    fn default() -> Self {
+        EmbeddingIndexConfig { metric: SimilarityMetric::Cosine, hnsw: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexedEmbedding {
+    pub id: String,
+    pub text: String,
+    pub metadata: BTreeMap<String, String>,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmbeddingSearchResult {
+    pub id: String,
+    pub text: String,
+    pub metadata: BTreeMap<String, String>,
+    pub score: f32,
+}
+
+// the links of a node by layer, persisted by id
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredNode {
+    neighbours: Vec<Vec<String>>,
+}
+
+struct Node {
+    id: String,
+    // normalized for cosine similarity
+    vector: Vec<f32>,
+    // one list per layer, empty without HNSW
+    neighbours: Vec<Vec<usize>>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Scored(f32, usize);
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norm = dot(a, a).sqrt() * dot(b, b).sqrt();
+    if norm > 0.0 { dot(a, b) / norm } else { 0.0 }
+}
+
+// null vectors are returned as they are
+pub fn normalize(vector: &[f32]) -> Vec<f32> {
+    let norm = dot(vector, vector).sqrt();
+    if norm > 0.0 { vector.iter().map(|x| x / norm).collect() } else { vector.to_vec() }
+}
+
+fn random_level(m: usize) -> usize {
+    let ml = 1.0 / (m.max(2) as f64).ln();
+    let level = -rand::random::<f64>().max(f64::MIN_POSITIVE).ln() * ml;
+    (level.floor() as usize).min(MAX_HNSW_LEVEL)
+}
+
+// slots of removed nodes are not reused until the index is compacted, so stale links to them can be skipped safely
+#[derive(Default)]
+struct IndexState {
+    nodes: Vec<Option<Node>>,
+    slots: HashMap<String, usize>,
+    entry_point: Option<usize>,
+    dimensions: Option<usize>,
+}
+
+impl IndexState {
+    fn node(&self, slot: usize) -> Option<&Node> {
+        self.nodes.get(slot).and_then(|x| x.as_ref())
+    }
+
+    fn level(&self, slot: usize) -> usize {
+        self.node(slot).map(|x| x.neighbours.len().saturating_sub(1)).unwrap_or(0)
+    }
+
+    fn similarity(&self, query: &[f32], slot: usize) -> f32 {
+        self.node(slot).map(|x| dot(query, &x.vector)).unwrap_or(f32::MIN)
+    }
+
+    fn neighbours(&self, slot: usize, layer: usize) -> Vec<usize> {
+        self.node(slot).and_then(|x| x.neighbours.get(layer)).map(|x| x.iter().copied().filter(|n| self.node(*n).is_some()).collect()).unwrap_or_default()
+    }
+
+    fn brute_force(&self, query: &[f32], k: usize) -> Vec<Scored> {
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for (slot, node) in self.nodes.iter().enumerate() {
+            if let Some(node) = node {
+                results.push(Reverse(Scored(dot(query, &node.vector), slot)));
+                if results.len() > k {
+                    results.pop();
+                }
+            }
+        }
+        let mut results = results.into_iter().map(|x| x.0).collect::<Vec<Scored>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    // the ef most similar nodes reachable from the entry points on one layer, best first
+    fn search_layer(&self, query: &[f32], entry_points: &[usize], ef: usize, layer: usize) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        let mut results: BinaryHeap<Reverse<Scored>> = BinaryHeap::new();
+        for slot in entry_points.iter() {
+            let scored = Scored(self.similarity(query, *slot), *slot);
+            candidates.push(scored);
+            results.push(Reverse(scored));
+        }
+        while let Some(candidate) = candidates.pop() {
+            let worst = results.peek().map(|x| x.0 .0).unwrap_or(f32::MIN);
+            if results.len() >= ef && candidate.0 < worst {
+                break;
+            }
+            for neighbour in self.neighbours(candidate.1, layer) {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let scored = Scored(self.similarity(query, neighbour), neighbour);
+                let worst = results.peek().map(|x| x.0 .0).unwrap_or(f32::MIN);
+                if results.len() < ef || scored.0 > worst {
+                    candidates.push(scored);
+                    results.push(Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        let mut results = results.into_iter().map(|x| x.0).collect::<Vec<Scored>>();
+        results.sort_by(|a, b| b.cmp(a));
+        results
+    }
+
+    fn hnsw_search(&self, query: &[f32], k: usize, ef: usize) -> Vec<Scored> {
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => return Vec::new(),
+        };
+        let mut entry_points = vec![entry_point];
+        for layer in (1..=self.level(entry_point)).rev() {
+            entry_points = vec![self.search_layer(query, &entry_points, 1, layer)[0].1];
+        }
+        let mut results = self.search_layer(query, &entry_points, ef.max(k), 0);
+        results.truncate(k);
+        results
+    }
+
+    // links `to` from `from`, keeping only the most similar neighbours if there are too many
+    fn add_link(&mut self, from: usize, to: usize, layer: usize, max_neighbours: usize) {
+        let mut neighbours = self.neighbours(from, layer);
+        if from == to || neighbours.contains(&to) || layer > self.level(from) {
+            return;
+        }
+        neighbours.push(to);
+        if neighbours.len() > max_neighbours {
+            let vector = self.node(from).map(|x| x.vector.clone()).unwrap_or_default();
+            let mut scored = neighbours.iter().map(|x| Scored(self.similarity(&vector, *x), *x)).collect::<Vec<Scored>>();
+            scored.sort_by(|a, b| b.cmp(a));
+            neighbours = scored.into_iter().take(max_neighbours).map(|x| x.1).collect();
+        }
+        if let Some(node) = self.nodes[from].as_mut() {
+            node.neighbours[layer] = neighbours;
+        }
+    }
+
+    // connects a node that is already stored in its slot, returns the slots whose links changed
+    fn link(&mut self, slot: usize, config: &HnswConfig) -> Vec<usize> {
+        let level = self.level(slot);
+        let entry_point = match self.entry_point {
+            Some(entry_point) => entry_point,
+            None => {
+                self.entry_point = Some(slot);
+                return vec![slot];
+            }
+        };
+        let query = self.node(slot).map(|x| x.vector.clone()).unwrap_or_default();
+        let top = self.level(entry_point);
+        let mut touched = vec![slot];
+        let mut entry_points = vec![entry_point];
+        for layer in (level + 1..=top).rev() {
+            entry_points = vec![self.search_layer(&query, &entry_points, 1, layer)[0].1];
+        }
+        for layer in (0..=level.min(top)).rev() {
+            let found = self.search_layer(&query, &entry_points, config.ef_construction, layer);
+            let selected = found.iter().map(|x| x.1).filter(|x| *x != slot).take(config.m).collect::<Vec<usize>>();
+            if let Some(node) = self.nodes[slot].as_mut() {
+                node.neighbours[layer] = selected.clone();
+            }
+            for neighbour in selected {
+                self.add_link(neighbour, slot, layer, config.max_neighbours(layer));
+                touched.push(neighbour);
+            }
+            entry_points = found.into_iter().map(|x| x.1).collect();
+        }
+        if level > top {
+            self.entry_point = Some(slot);
+        }
+        touched
+    }
+
+    // removes a node and connects its former neighbours among each other, returns the slots whose links changed
+    fn remove(&mut self, slot: usize, config: Option<&HnswConfig>) -> Vec<usize> {
+        let node = match self.nodes.get_mut(slot).and_then(|x| x.take()) {
+            Some(node) => node,
+            None => return Vec::new(),
+        };
+        self.slots.remove(&node.id);
+        let mut touched = Vec::new();
+        if let Some(config) = config {
+            for (layer, neighbours) in node.neighbours.iter().enumerate() {
+                for from in neighbours.iter() {
+                    if let Some(neighbour) = self.nodes.get_mut(*from).and_then(|x| x.as_mut()) {
+                        if let Some(links) = neighbour.neighbours.get_mut(layer) {
+                            links.retain(|x| *x != slot);
+                        }
+                        touched.push(*from);
+                    }
+                }
+                for from in neighbours.iter() {
+                    for to in neighbours.iter() {
+                        if self.node(*from).is_some() && self.node(*to).is_some() {
+                            self.add_link(*from, *to, layer, config.max_neighbours(layer));
+                        }
+                    }
+                }
+            }
+        }
+        if self.entry_point == Some(slot) {
+            self.entry_point = self.nodes.iter().enumerate()
+                .filter(|(_, x)| x.is_some())
+                .max_by_key(|(i, _)| (self.level(*i), Reverse(*i)))
+                .map(|(i, _)| i);
+        }
+        touched
+    }
+
+    // drops the slots of removed nodes once they outnumber the live ones and remaps the links,
+    // links are persisted by id so nothing has to be written
+    fn compact(&mut self) {
+        if self.nodes.len() <= 2 * self.slots.len() + MIN_COMPACTION_SLOTS {
+            return;
+        }
+        let mut remap = vec![None; self.nodes.len()];
+        let mut nodes = Vec::with_capacity(self.slots.len());
+        for (slot, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if let Some(node) = node {
+                remap[slot] = Some(nodes.len());
+                nodes.push(Some(node));
+            }
+        }
+        for node in nodes.iter_mut().flatten() {
+            for layer in node.neighbours.iter_mut() {
+                *layer = layer.iter().filter_map(|x| remap[*x]).collect();
+            }
+        }
+        self.slots = nodes.iter().enumerate().filter_map(|(slot, x)| x.as_ref().map(|x| (x.id.to_owned(), slot))).collect();
+        self.entry_point = self.entry_point.and_then(|x| remap[x]);
+        self.nodes = nodes;
+    }
+}
+
+// vectors with their text and metadata in a sled tree, searchable by similarity,
+// the HNSW graph is kept in a second tree so it survives restarts
+pub struct EmbeddingIndex {
+    name: String,
+    config: EmbeddingIndexConfig,
+    tree: sled::Tree,
+    hnsw_tree: sled::Tree,
+    state: RwLock<IndexState>,
+}
+
+impl EmbeddingIndex {
+    pub fn open(db: &sled::Db, name: &str, config: EmbeddingIndexConfig) -> anyhow::Result<Self> {
+        let tree = db.open_tree(format!("embedding_index/{}", name))?;
+        let hnsw_tree = db.open_tree(format!("embedding_index/{}/hnsw", name))?;
+        let mut state = IndexState::default();
+        for item in tree.iter() {
+            let (_, value) = item?;
+            let embedding: IndexedEmbedding = bincode::deserialize(&value)?;
+            state.dimensions = Some(embedding.vector.len());
+            state.slots.insert(embedding.id.to_owned(), state.nodes.len());
+            state.nodes.push(Some(Node {
+                vector: Self::prepare(config.metric, &embedding.vector),
+                id: embedding.id,
+                neighbours: Vec::new(),
+            }));
+        }
+        let index = EmbeddingIndex { name: name.to_string(), config, tree, hnsw_tree, state: RwLock::new(state) };
+        if let Some(hnsw) = config.hnsw {
+            index.load_graph(&hnsw)?;
+        }
+        Ok(index)
+    }
+
+    fn prepare(metric: SimilarityMetric, vector: &[f32]) -> Vec<f32> {
+        match metric {
+            SimilarityMetric::Cosine => normalize(vector),
+            SimilarityMetric::DotProduct => vector.to_vec(),
+        }
+    }
+
+    // restores the persisted links, nodes without any (e.g. added while the index ran without HNSW) are linked anew
+    fn load_graph(&self, config: &HnswConfig) -> anyhow::Result<()> {
+        let mut state = self.state.write().map_err(|_| anyhow::anyhow!("Error: embedding index '{}' unavailable", self.name))?;
+        let mut unlinked = Vec::new();
+        for slot in 0..state.nodes.len() {
+            let id = state.nodes[slot].as_ref().map(|x| x.id.to_owned()).unwrap_or_default();
+            let neighbours = match self.hnsw_tree.get(id.as_bytes())? {
+                Some(value) => bincode::deserialize::<StoredNode>(&value)?.neighbours.into_iter()
+                    .map(|layer| layer.iter().filter_map(|x| state.slots.get(x).copied()).collect::<Vec<usize>>())
+                    .collect::<Vec<Vec<usize>>>(),
+                None => Vec::new(),
+            };
+            if neighbours.is_empty() {
+                unlinked.push(slot);
+            } else if let Some(node) = state.nodes[slot].as_mut() {
+                node.neighbours = neighbours;
+            }
+        }
+        state.entry_point = (0..state.nodes.len())
+            .filter(|x| !unlinked.contains(x))
+            .max_by_key(|x| (state.level(*x), Reverse(*x)));
+        let mut touched = Vec::new();
+        for slot in unlinked {
+            if let Some(node) = state.nodes[slot].as_mut() {
+                node.neighbours = vec![Vec::new(); random_level(config.m) + 1];
+            }
+            touched.append(&mut state.link(slot, config));
+        }
+        self.persist_links(&state, touched)
+    }
+
+    fn persist_links(&self, state: &IndexState, mut slots: Vec<usize>) -> anyhow::Result<()> {
+        slots.sort_unstable();
+        slots.dedup();
+        for slot in slots {
+            if let Some(node) = state.node(slot) {
+                let stored = StoredNode {
+                    neighbours: node.neighbours.iter()
+                        .map(|layer| layer.iter().filter_map(|x| state.node(*x).map(|x| x.id.to_owned())).collect())
+                        .collect(),
+                };
+                self.hnsw_tree.insert(node.id.as_bytes(), bincode::serialize(&stored)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &EmbeddingIndexConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.read().map(|x| x.slots.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<IndexedEmbedding>> {
+        Ok(match self.tree.get(id.as_bytes())? {
+            Some(value) => Some(bincode::deserialize(&value)?),
+            None => None,
+        })
+    }
+
+    // inserts the embedding or replaces the one with the same id
+    pub fn upsert(&self, id: &str, text: &str, metadata: BTreeMap<String, String>, vector: Vec<f32>) -> anyhow::Result<()> {
+        let mut state = self.state.write().map_err(|_| anyhow::anyhow!("Error: embedding index '{}' unavailable", self.name))?;
+        if vector.is_empty() {
+            return Err(anyhow::anyhow!("Error: empty embedding for '{}'", id));
+        }
+        match state.dimensions {
+            Some(dimensions) if dimensions != vector.len() => {
+                return Err(anyhow::anyhow!("Error: embedding index '{}' holds {} dimensions, '{}' has {}", self.name, dimensions, id, vector.len()));
+            }
+            _ => state.dimensions = Some(vector.len()),
+        }
+        let mut touched = Vec::new();
+        if let Some(slot) = state.slots.get(id).copied() {
+            touched.append(&mut state.remove(slot, self.config.hnsw.as_ref()));
+        }
+
+        let embedding = IndexedEmbedding { id: id.to_string(), text: text.to_string(), metadata, vector };
+        self.tree.insert(id.as_bytes(), bincode::serialize(&embedding)?)?;
+
+        let slot = state.nodes.len();
+        let level = self.config.hnsw.map(|x| random_level(x.m) + 1).unwrap_or(0);
+        state.nodes.push(Some(Node {
+            id: embedding.id,
+            vector: Self::prepare(self.config.metric, &embedding.vector),
+            neighbours: vec![Vec::new(); level],
+        }));
+        state.slots.insert(id.to_string(), slot);
+        if let Some(hnsw) = self.config.hnsw {
+            touched.append(&mut state.link(slot, &hnsw));
+            self.persist_links(&state, touched)?;
+        }
+        state.compact();
+        Ok(())
+    }
+
+    pub fn delete(&self, id: &str) -> anyhow::Result<bool> {
+        let mut state = self.state.write().map_err(|_| anyhow::anyhow!("Error: embedding index '{}' unavailable", self.name))?;
+        let slot = match state.slots.get(id).copied() {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+        let touched = state.remove(slot, self.config.hnsw.as_ref());
+        self.tree.remove(id.as_bytes())?;
+        self.hnsw_tree.remove(id.as_bytes())?;
+        if self.config.hnsw.is_some() {
+            self.persist_links(&state, touched)?;
+        }
+        if state.slots.is_empty() {
+            state.dimensions = None;
+        }
+        state.compact();
+        Ok(true)
+    }
+
+    // the k most similar embeddings, approximate if the index uses HNSW
+    pub fn search(&self, query: &[f32], k: usize) -> anyhow::Result<Vec<EmbeddingSearchResult>> {
+        match self.config.hnsw {
+            Some(hnsw) => self.search_with(query, k, |state, query| state.hnsw_search(query, k, hnsw.ef_search)),
+            None => self.search_exact(query, k),
+        }
+    }
+
+    pub fn search_exact(&self, query: &[f32], k: usize) -> anyhow::Result<Vec<EmbeddingSearchResult>> {
+        self.search_with(query, k, |state, query| state.brute_force(query, k))
+    }
+
+    fn search_with<F>(&self, query: &[f32], k: usize, search: F) -> anyhow::Result<Vec<EmbeddingSearchResult>>
+        where
+            F: Fn(&IndexState, &[f32]) -> Vec<Scored>,
+    {
+        let state = self.state.read().map_err(|_| anyhow::anyhow!("Error: embedding index '{}' unavailable", self.name))?;
+        if let Some(dimensions) = state.dimensions {
+            if dimensions != query.len() {
+                return Err(anyhow::anyhow!("Error: embedding index '{}' holds {} dimensions, the query has {}", self.name, dimensions, query.len()));
+            }
+        }
+        if k == 0 {
+            return Ok(Vec::new());
+        }
+        let query = Self::prepare(self.config.metric, query);
+        let mut results = Vec::new();
+        for scored in search(&state, &query) {
+            let id = match state.node(scored.1) {
+                Some(node) => &node.id,
+                None => continue,
+            };
+            if let Some(embedding) = self.get(id)? {
+                results.push(EmbeddingSearchResult { id: embedding.id, text: embedding.text, metadata: embedding.metadata, score: scored.0 });
+            }
+        }
+        Ok(results)
+    }
+}
+
+// an index stored next to the result store in SLED_DB
+pub fn open_embedding_index(name: &str, config: EmbeddingIndexConfig) -> anyhow::Result<EmbeddingIndex> {
+    EmbeddingIndex::open(&SLED_DB, name, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_index(hnsw: Option<HnswConfig>) -> EmbeddingIndex {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        EmbeddingIndex::open(&db, "test", EmbeddingIndexConfig { metric: SimilarityMetric::Cosine, hnsw }).unwrap()
+    }
+
+    fn vector(i: usize) -> Vec<f32> {
+        let angle = i as f32 * 0.1;
+        vec![angle.cos(), angle.sin(), 0.5]
+    }
+
+    #[test]
+    fn upserts_and_deletes_compact_the_index() {
+        let index = test_index(Some(HnswConfig { m: 4, ef_construction: 16, ef_search: 16 }));
+        for i in 0..20 {
+            index.upsert(&i.to_string(), "", BTreeMap::new(), vector(i)).unwrap();
+        }
+        for round in 0..20 {
+            for i in 0..20 {
+                index.upsert(&i.to_string(), "", BTreeMap::new(), vector(i + round)).unwrap();
+            }
+        }
+        for i in 10..20 {
+            assert!(index.delete(&i.to_string()).unwrap());
+        }
+        let state = index.state.read().unwrap();
+        assert!(state.nodes.len() <= 2 * state.slots.len() + MIN_COMPACTION_SLOTS + 1);
+        assert_eq!(state.slots.len(), 10);
+        for (id, slot) in state.slots.iter() {
+            let node = state.node(*slot).unwrap();
+            assert_eq!(&node.id, id);
+            // links to removed slots are skipped, but never point past the compacted nodes
+            assert!(node.neighbours.iter().flatten().all(|x| *x < state.nodes.len()));
+        }
+        drop(state);
+        let results = index.search(&vector(25), 1).unwrap();
+        assert_eq!(results[0].id, "6");
+    }
+
+    #[test]
+    fn hnsw_finds_the_exact_nearest_neighbour() {
+        let index = test_index(Some(HnswConfig::default()));
+        for i in 0..50 {
+            index.upsert(&i.to_string(), &format!("text {}", i), BTreeMap::new(), vector(i)).unwrap();
+        }
+        let exact = index.search_exact(&vector(17), 3).unwrap();
+        let approximate = index.search(&vector(17), 3).unwrap();
+        assert_eq!(exact[0].id, "17");
+        assert_eq!(exact.iter().map(|x| &x.id).collect::<Vec<_>>(), approximate.iter().map(|x| &x.id).collect::<Vec<_>>());
+    }
+}
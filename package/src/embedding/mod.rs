@@ -1,15 +1,55 @@
+pub mod index;
+pub mod cache;
+pub mod analysis;
 
-use reqwest::Client;
-use reqwest::header::HeaderValue;
-use reqwest::header::CONTENT_TYPE;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use base64::Engine;
+use rust_openai_gpt_tools_socket_ipc::ipc::OpenAIGPTEmbeddingEncoding;
+use serde::Deserializer;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::api::{post_json, PartiallyBilled};
+use crate::tokenizer::count_tokens;
+use cache::EMBEDDING_CACHE;
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+// OpenAI accepts at most 2048 inputs per request
+pub const EMBEDDING_BATCH_SIZE: usize = 2048;
+pub const EMBEDDING_BATCH_TOKENS: usize = 100_000;
+pub const EMBEDDING_CONCURRENCY: usize = 4;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Embedding {
     object: String,
-    index: u32,
+    pub index: u32,
+    #[serde(deserialize_with = "deserialize_embedding")]
     pub embedding: Vec<f32>,
 }
 
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum EncodedEmbedding {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+// accepts both encoding formats, base64 is a string of little-endian f32s
+fn deserialize_embedding<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+    match <EncodedEmbedding as serde::Deserialize>::deserialize(deserializer)? {
+        EncodedEmbedding::Float(embedding) => Ok(embedding),
+        EncodedEmbedding::Base64(encoded) => {
+            let bytes = base64::engine::general_purpose::STANDARD.decode(encoded).map_err(serde::de::Error::custom)?;
+            if bytes.len() % 4 != 0 {
+                return Err(serde::de::Error::custom("base64 embedding is not a sequence of f32"));
+            }
+            Ok(bytes.chunks_exact(4).map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]])).collect())
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct EmbeddingData {
     object: String,
@@ -25,23 +65,135 @@ pub struct Usage {
     pub total_tokens: i64,
 }
 
+// index ranges of the texts, each batch within max_size inputs and max_tokens tokens, a single larger text is a batch of its own
+pub fn batch_ranges(model_name: &str, texts: &[String], max_size: usize, max_tokens: usize) -> Vec<(usize, usize)> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut tokens = 0;
+    for (i, text) in texts.iter().enumerate() {
+        let text_tokens = count_tokens(model_name, text);
+        if i > start && (i - start >= max_size || tokens + text_tokens > max_tokens) {
+            batches.push((start, i));
+            start = i;
+            tokens = 0;
+        }
+        tokens += text_tokens;
+    }
+    if start < texts.len() {
+        batches.push((start, texts.len()));
+    }
+    batches
+}
 
-pub async fn embedding_endpoint(texts: Vec<String>) -> anyhow::Result<EmbeddingData> {
+// the namespace of the embeddings in the cache, both encoding formats decode to the same embeddings
+pub fn cache_model_key(model_name: &str, dimensions: Option<u32>) -> String {
+    match dimensions {
+        Some(dimensions) => format!("{}:{}", model_name, dimensions),
+        None => model_name.to_string(),
+    }
+}
 
-    let json_data = serde_json::json!({
+async fn embedding_request(model_name: String, texts: Vec<String>, dimensions: Option<u32>, encoding_format: OpenAIGPTEmbeddingEncoding) -> anyhow::Result<EmbeddingData> {
+
+    let mut json_data = serde_json::json!({
         "input": texts,
-        "model": "text-embedding-ada-002"
+        "model": model_name,
+        "encoding_format": match encoding_format {
+            OpenAIGPTEmbeddingEncoding::Float => "float",
+            OpenAIGPTEmbeddingEncoding::Base64 => "base64",
+        }
     });
+    if let Some(dimensions) = dimensions {
+        json_data["dimensions"] = serde_json::json!(dimensions);
+    }
+
+    let embedding = post_json::<EmbeddingData>("https://api.openai.com/v1/embeddings", &model_name, texts.iter().map(|x| count_tokens(&model_name, x) as u64).sum(), &json_data).await?;
+
+    Ok(embedding)
+}
 
-    let client = Client::new();
-    let url = "https://api.openai.com/v1/embeddings";
-    let response = client.post(url)
-        .bearer_auth(&super::ENV.openai_api_key)
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(json_data.to_string())
-        .send().await;
+// large inputs are split into batches that run concurrently, the embeddings are in input order
+pub async fn embedding_endpoint(model_name: &str, texts: Vec<String>, dimensions: Option<u32>, encoding_format: OpenAIGPTEmbeddingEncoding) -> anyhow::Result<EmbeddingData> {
 
-    let embedding = response?.json::<EmbeddingData>().await?;
+    let batches = batch_ranges(model_name, &texts, EMBEDDING_BATCH_SIZE, EMBEDDING_BATCH_TOKENS);
+    let expected = texts.len();
+    let mut embedding = if batches.len() <= 1 {
+        embedding_request(model_name.to_string(), texts, dimensions, encoding_format).await?
+    } else {
+        let semaphore = Arc::new(Semaphore::new(EMBEDDING_CONCURRENCY));
+        // dropping the set on the first error aborts the batches that are still running
+        let mut tasks = JoinSet::new();
+        for (start, end) in batches {
+            let batch = texts[start..end].to_vec();
+            let semaphore = semaphore.clone();
+            let model_name = model_name.to_string();
+            tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                embedding_request(model_name, batch, dimensions, encoding_format).await.map(|x| (start, x))
+            });
+        }
 
+        let mut embedding: Option<EmbeddingData> = None;
+        while let Some(joined) = tasks.join_next().await {
+            let (start, mut batch_embedding) = match joined.map_err(anyhow::Error::from).and_then(|x| x) {
+                Ok(o) => o,
+                // the batches that returned before are billed all the same
+                Err(error) => return Err(match embedding {
+                    Some(embedding) => PartiallyBilled { usages: vec![embedding.usage], error }.into(),
+                    None => error,
+                }),
+            };
+            for item in batch_embedding.data.iter_mut() {
+                item.index += start as u32;
+            }
+            match embedding.as_mut() {
+                Some(embedding) => {
+                    embedding.data.append(&mut batch_embedding.data);
+                    embedding.usage.prompt_tokens += batch_embedding.usage.prompt_tokens;
+                    embedding.usage.total_tokens += batch_embedding.usage.total_tokens;
+                }
+                None => embedding = Some(batch_embedding),
+            }
+        }
+        embedding.ok_or_else(|| anyhow::anyhow!("Error: nothing to embed"))?
+    };
+    embedding.data.sort_by_key(|x| x.index);
+    if embedding.data.len() != expected {
+        return Err(anyhow::anyhow!("Error: expected {} embeddings, got {}", expected, embedding.data.len()));
+    }
     Ok(embedding)
 }
+
+// like embedding_endpoint, but only texts that were not embedded before are sent to OpenAI,
+// the usage covers the texts that were fetched, None if all were cached, a failure may be PartiallyBilled
+pub async fn cached_embedding_endpoint(model_name: &str, texts: &[String], dimensions: Option<u32>, encoding_format: OpenAIGPTEmbeddingEncoding) -> anyhow::Result<(Vec<Vec<f32>>, Option<Usage>)> {
+    let cache_key = cache_model_key(model_name, dimensions);
+    let mut embeddings: Vec<Option<Vec<f32>>> = Vec::new();
+    for text in texts.iter() {
+        embeddings.push(EMBEDDING_CACHE.get(&cache_key, text).unwrap_or(None));
+    }
+
+    // the same text twice in one request is fetched once, in order of first appearance
+    let mut missing: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut unique: Vec<&str> = Vec::new();
+    for (i, embedding) in embeddings.iter().enumerate() {
+        if embedding.is_none() {
+            missing.entry(texts[i].as_str()).or_insert_with(|| {
+                unique.push(texts[i].as_str());
+                Vec::new()
+            }).push(i);
+        }
+    }
+    let mut usage = None;
+    if !unique.is_empty() {
+        let embedding_data = embedding_endpoint(model_name, unique.iter().map(|x| x.to_string()).collect(), dimensions, encoding_format).await?;
+        for (text, embedding) in unique.into_iter().zip(embedding_data.data) {
+            EMBEDDING_CACHE.insert(&cache_key, text, &embedding.embedding).ok();
+            for i in missing.get(text).into_iter().flatten() {
+                embeddings[*i] = Some(embedding.embedding.clone());
+            }
+        }
+        usage = Some(embedding_data.usage);
+    }
+    Ok((embeddings.into_iter().flatten().collect(), usage))
+}
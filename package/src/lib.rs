@@ -5,6 +5,22 @@ pub mod moderation;
 pub mod text_completion;
 pub mod embedding;
 pub mod chat_completion;
+pub mod budget;
+pub mod throttle;
+pub mod api;
+pub mod tokenizer;
+pub mod models;
+pub mod routing;
+pub mod pre_moderation;
+pub mod rag;
+pub mod summarization;
+pub mod prompt;
+pub mod injection;
+pub mod fraud;
+pub mod tasks;
+pub mod conversation;
+pub mod agent;
+pub mod pipeline;
 
 
 use std::env;
@@ -17,8 +33,6 @@ lazy_static!{
    pub static ref ENV: Env = load_env();
 }
 
-pub const MAX_TOKENS: u16 = 4000u16;
-
 pub struct Env {
     pub openai_api_key: String
 }
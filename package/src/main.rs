@@ -11,7 +11,7 @@ use std::env;
 //      sudo docker run -it --rm -v "$(pwd)/rustbert_cache":/usr/rustbert_cache -v "$(pwd)/target":/usr/target -v "$(pwd)/cargo_home":/usr/cargo_home -v "$(pwd)/package":/usr/workspace -v "$(pwd)/tmp":/usr/workspace/tmp -v "$(pwd)/socket_ipc":/usr/socket_ipc rust-bert-fraud-detection cargo run --release test_service
 
 
-use rust_openai_gpt_tools::embedding::embedding_endpoint;
+use rust_openai_gpt_tools::embedding::{embedding_endpoint, DEFAULT_EMBEDDING_MODEL};
 
 /*
 use rust_openai_gpt_tools::text_completion::{completion_endpoint};
@@ -22,8 +22,9 @@ use rust_openai_gpt_tools::service::moderated_chat_completion_endpoint;
 */
 
 use rust_openai_gpt_tools::service::spawn_openai_gpt_api_socket_service;
-use rust_openai_gpt_tools_socket_ipc::ipc::{client_send_openai_gpt_embedding_request, client_send_openai_gpt_text_completion_request, client_send_openai_gpt_chat_completion_request};
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTEmbeddingEncoding, client_send_openai_gpt_embedding_request, client_send_openai_gpt_text_completion_request, client_send_openai_gpt_chat_completion_request, client_send_openai_gpt_fraud_classification_request, client_send_openai_gpt_chat_session_request, client_send_openai_gpt_throttle_status_request};
 
+#[allow(dead_code)]
 const PROMPTS: [&str;2] = [
     "Describe how this proposal may be perceived by the community, including potential reactions of both acceptance and rejection.",
     "Describe the proposal in a nutshell, including the most important points to consider when deciding whether to support it or not."];
@@ -36,7 +37,7 @@ async fn main() -> anyhow::Result<()> {
     println!("env::args().collect(): {:?}",args);
 
     if args.len() <= 1 {
-        let result = embedding_endpoint(vec!["the fish has its journey to the moon.".to_string()]).await?;
+        let result = embedding_endpoint(DEFAULT_EMBEDDING_MODEL, vec!["the fish has its journey to the moon.".to_string()], None, OpenAIGPTEmbeddingEncoding::Float).await?;
         println!("{:?}",result);
         //my_completion_endpoint(&format!("<proposal>{}</proposal><result description='{}'>",TEST,PROMPTS[1]), 100).await?;
         Ok(())
@@ -48,13 +49,7 @@ async fn main() -> anyhow::Result<()> {
             },
             "test_service_chat" => {
 
-                let mut texts = Vec::new();
-
-                for i in 0..args.len(){
-                    if i > 1 {
-                        texts.push(args[i].to_owned());
-                    }
-                }
+                let texts: Vec<String> = args.iter().skip(2).cloned().collect();
 
                 /*
                 <instruction>Summarize the following</instruction><source>Redacted: Redacted Money allows users to grant themselves anonymity through a trustless decentralized smart contract based on a zero-Knowledge solution. This proposal to the community aims to achieve Redacted's goals. The main objective is to deliver an Inter- and Cross-chain privacy solution that is going to provide a use case for people using all sorts of chains being routed through Terra and thereby driving volume, which offers anonymous bridging. We will achieve this by integrating off-chain tokens through partners like Axelar and make mixing from and to other chains possible. This is unprecedented in the privacy space. The requested 400,000 $LUNA is given to us in exchange for 1,000,000 (1M) $RED (10% of the supply) to a multi-sig managed by the community (later Community Pool) and will be divided as follows: 50% upfront for runway to finish urgent tasks like finishing revenue sharing and starting cross-chain compatibility - 50% is vested linearly over a period of three (3) months to finish cross-chain compatibility and improving privacy for $LUNA redactors especially. More info about our Goals and spends can be found in our agora discussion: https://agora.terra.money/discussion/7675-redacted-grant-proposal-progress-so-far</source>\n\n<result>let brief_overview: &str  = r#\"
@@ -73,15 +68,43 @@ Attributes:
             }
             "test_service_prompt" => {
 
-                let mut texts = Vec::new();
+                let texts: Vec<String> = args.iter().skip(2).cloned().collect();
 
-                for i in 0..args.len(){
-                    if i > 1 {
-                        texts.push(args[i].to_owned());
+                let result = client_send_openai_gpt_text_completion_request("./tmp/rust_openai_gpt_tools_socket", texts[0].clone(), 100)?;
+                println!("{:?}",result);
+                Ok(())
+            }
+            "test_service_fraud" => {
+
+                let text = match args.get(2) {
+                    Some(text) => text.to_owned(),
+                    None => {
+                        println!("usage: test_service_fraud <text>");
+                        return Ok(());
                     }
-                }
+                };
 
-                let result = client_send_openai_gpt_text_completion_request("./tmp/rust_openai_gpt_tools_socket", texts[0].clone(), 100)?;
+                let result = client_send_openai_gpt_fraud_classification_request("./tmp/rust_openai_gpt_tools_socket", text)?;
+                println!("{:?}",result);
+                Ok(())
+            }
+            "test_service_session" => {
+
+                let (session_id, message) = match (args.get(2), args.get(3)) {
+                    (Some(session_id), Some(message)) => (session_id.to_owned(), message.to_owned()),
+                    _ => {
+                        println!("usage: test_service_session <session id> <message>");
+                        return Ok(());
+                    }
+                };
+
+                let result = client_send_openai_gpt_chat_session_request("./tmp/rust_openai_gpt_tools_socket", session_id, message)?;
+                println!("{:?}",result);
+                Ok(())
+            }
+            "test_service_throttle" => {
+
+                let result = client_send_openai_gpt_throttle_status_request("./tmp/rust_openai_gpt_tools_socket")?;
                 println!("{:?}",result);
                 Ok(())
             }
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::embedding::Usage;
+
+pub const MODEL_REGISTRY_PATH: &str = "./tmp/rust_openai_gpt_tools_models.json";
+
+lazy_static!{
+   pub static ref MODEL_REGISTRY: ModelRegistry = load_model_registry(MODEL_REGISTRY_PATH);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModelEndpoint {
+    Chat,
+    Completion,
+    Embedding,
+    Moderation,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelPricing {
+    pub prompt_per_1k_token: f64,
+    pub completion_per_1k_token: f64,
+}
+
+// conservative defaults for the throttle, replaced as soon as OpenAI reports the actual limits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimits {
+    pub requests_per_minute: u64,
+    pub tokens_per_minute: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelInfo {
+    pub name: String,
+    pub family: String,
+    pub context_window: usize,
+    #[serde(default)]
+    pub max_output_tokens: Option<usize>,
+    pub endpoints: Vec<ModelEndpoint>,
+    #[serde(default)]
+    pub supports_tools: bool,
+    #[serde(default)]
+    pub supports_json_mode: bool,
+    #[serde(default)]
+    pub supports_vision: bool,
+    #[serde(default)]
+    pub pricing: ModelPricing,
+    // relative output quality used for routing, higher is better
+    #[serde(default)]
+    pub quality: u8,
+    // "YYYY-MM-DD", after this date requests are mapped to the replacement
+    #[serde(default)]
+    pub deprecation_date: Option<String>,
+    #[serde(default)]
+    pub replacement: Option<String>,
+    #[serde(default)]
+    pub rate_limits: Option<RateLimits>,
+}
+
+impl ModelInfo {
+    pub fn supports(&self, endpoint: ModelEndpoint) -> bool {
+        self.endpoints.contains(&endpoint)
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecation_date.as_ref().map(|x| x.as_str() <= today().as_str()).unwrap_or(false)
+    }
+
+    pub fn costs(&self, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        (prompt_tokens as f64 * self.pricing.prompt_per_1k_token + completion_tokens as f64 * self.pricing.completion_per_1k_token) / 1000.0
+    }
+
+    pub fn usage_costs(&self, usage: &Usage) -> f64 {
+        self.costs(usage.prompt_tokens.max(0) as u64, usage.completion_tokens.unwrap_or(0).max(0) as u64)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelRegistryConfig {
+    #[serde(default)]
+    pub models: Vec<ModelInfo>,
+    // overrides the replacement of deprecated models
+    #[serde(default)]
+    pub replacements: HashMap<String, String>,
+}
+
+// the requested model can not serve the request, routing may fall back to another one
+#[derive(Debug, Clone)]
+pub struct ModelUnavailable(pub String);
+
+impl std::fmt::Display for ModelUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ModelUnavailable {}
+
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    models: HashMap<String, ModelInfo>,
+}
+
+impl ModelRegistry {
+    pub fn new(models: Vec<ModelInfo>) -> Self {
+        ModelRegistry {
+            models: models.into_iter().map(|x| (x.name.to_owned(), x)).collect(),
+        }
+    }
+
+    pub fn insert(&mut self, model: ModelInfo) {
+        self.models.insert(model.name.to_owned(), model);
+    }
+
+    pub fn models(&self) -> Vec<&ModelInfo> {
+        let mut models = self.models.values().collect::<Vec<&ModelInfo>>();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        models
+    }
+
+    // dated snapshots like "gpt-4-0613" fall back to the longest registered prefix
+    pub fn get(&self, model_name: &str) -> Option<&ModelInfo> {
+        if let Some(model) = self.models.get(model_name) {
+            return Some(model);
+        }
+        self.models.values()
+            .filter(|x| model_name.starts_with(&format!("{}-", x.name)))
+            .max_by_key(|x| x.name.len())
+    }
+
+    pub fn context_window(&self, model_name: &str) -> Option<usize> {
+        self.get(model_name).map(|x| x.context_window)
+    }
+
+    // follows the replacements of deprecated models
+    pub fn resolve(&self, model_name: &str) -> anyhow::Result<&ModelInfo> {
+        let mut model = self.get(model_name)
+            .ok_or_else(|| ModelUnavailable(format!("Error: unknown model '{}'", model_name)))?;
+        let mut visited = vec![model.name.to_owned()];
+        while model.is_deprecated() {
+            let replacement = match &model.replacement {
+                Some(replacement) => replacement,
+                None => return Err(ModelUnavailable(format!("Error: model '{}' is deprecated since {} and has no replacement", model.name, model.deprecation_date.as_ref().unwrap())).into()),
+            };
+            if visited.contains(replacement) {
+                return Err(anyhow::anyhow!("Error: cyclic model replacement for '{}'", model_name));
+            }
+            println!("Model '{}' is deprecated, using '{}' instead", model.name, replacement);
+            model = self.get(replacement)
+                .ok_or_else(|| ModelUnavailable(format!("Error: unknown replacement model '{}'", replacement)))?;
+            visited.push(model.name.to_owned());
+        }
+        Ok(model)
+    }
+
+    pub fn validate(&self, model_name: &str, endpoint: ModelEndpoint) -> anyhow::Result<ModelInfo> {
+        let model = self.resolve(model_name)?;
+        if !model.supports(endpoint) {
+            return Err(ModelUnavailable(format!("Error: model '{}' does not support the {:?} endpoint", model.name, endpoint)).into());
+        }
+        Ok(model.clone())
+    }
+}
+
+fn model(name: &str, family: &str, context_window: usize, max_output_tokens: Option<usize>, endpoints: Vec<ModelEndpoint>, prompt_per_1k_token: f64, completion_per_1k_token: f64) -> ModelInfo {
+    ModelInfo {
+        name: name.to_string(),
+        family: family.to_string(),
+        context_window,
+        max_output_tokens,
+        endpoints,
+        supports_tools: false,
+        supports_json_mode: false,
+        supports_vision: false,
+        pricing: ModelPricing { prompt_per_1k_token, completion_per_1k_token },
+        quality: 0,
+        deprecation_date: None,
+        replacement: None,
+        rate_limits: None,
+    }
+}
+
+fn limits(requests_per_minute: u64, tokens_per_minute: u64) -> Option<RateLimits> {
+    Some(RateLimits { requests_per_minute, tokens_per_minute })
+}
+
+pub fn default_models() -> Vec<ModelInfo> {
+    use ModelEndpoint::*;
+    vec![
+        ModelInfo { supports_tools: true, quality: 8, rate_limits: limits(200, 40_000), ..model("gpt-4", "gpt-4", 8_192, None, vec![Chat], 0.03, 0.06) },
+        ModelInfo { supports_tools: true, quality: 8, rate_limits: limits(200, 80_000), ..model("gpt-4-32k", "gpt-4", 32_768, None, vec![Chat], 0.06, 0.12) },
+        ModelInfo { supports_tools: true, supports_json_mode: true, supports_vision: true, quality: 9, ..model("gpt-4-turbo", "gpt-4", 128_000, Some(4_096), vec![Chat], 0.01, 0.03) },
+        ModelInfo { supports_tools: true, supports_json_mode: true, supports_vision: true, quality: 9, ..model("gpt-4o", "gpt-4o", 128_000, Some(16_384), vec![Chat], 0.0025, 0.01) },
+        ModelInfo { supports_tools: true, supports_json_mode: true, supports_vision: true, quality: 6, ..model("gpt-4o-mini", "gpt-4o", 128_000, Some(16_384), vec![Chat], 0.00015, 0.0006) },
+        ModelInfo { supports_tools: true, supports_json_mode: true, quality: 5, rate_limits: limits(3_500, 90_000), ..model("gpt-3.5-turbo", "gpt-3.5", 16_385, Some(4_096), vec![Chat], 0.002, 0.002) },
+        model("gpt-3.5-turbo-instruct", "gpt-3.5", 4_096, None, vec![Completion], 0.0015, 0.002),
+        ModelInfo {
+            deprecation_date: Some("2024-01-04".to_string()),
+            replacement: Some("gpt-3.5-turbo-instruct".to_string()),
+            rate_limits: limits(3_000, 250_000),
+            ..model("text-davinci-003", "gpt-3", 4_097, None, vec![Completion], 0.02, 0.02)
+        },
+        ModelInfo { rate_limits: limits(3_000, 1_000_000), ..model("text-embedding-ada-002", "text-embedding", 8_191, None, vec![Embedding], 0.0004, 0.0) },
+        model("text-embedding-3-small", "text-embedding", 8_191, None, vec![Embedding], 0.00002, 0.0),
+        model("text-embedding-3-large", "text-embedding", 8_191, None, vec![Embedding], 0.00013, 0.0),
+        ModelInfo { rate_limits: limits(1_000, 150_000), ..model("text-moderation-latest", "text-moderation", 32_768, None, vec![Moderation], 0.0, 0.0) },
+    ]
+}
+
+pub fn load_model_registry(path: &str) -> ModelRegistry {
+    let mut registry = ModelRegistry::new(default_models());
+    let config = match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<ModelRegistryConfig>(&json) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Error: invalid model registry at '{}': {}, using defaults", path, err);
+                ModelRegistryConfig::default()
+            }
+        },
+        Err(_) => ModelRegistryConfig::default(),
+    };
+    for model in config.models {
+        registry.insert(model);
+    }
+    for (model_name, replacement) in config.replacements {
+        if let Some(model) = registry.models.get_mut(&model_name) {
+            model.replacement = Some(replacement);
+        }
+    }
+    registry
+}
+
+// the current UTC date as "YYYY-MM-DD"
+pub fn today() -> String {
+    date_from_days(SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0) as i64 / 86_400)
+}
+
+// formats days since 1970-01-01 as "YYYY-MM-DD"
+fn date_from_days(days: i64) -> String {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date_from_days(0), "1970-01-01");
+        assert_eq!(date_from_days(59), "1970-03-01");
+        assert_eq!(date_from_days(11_016), "2000-02-29");
+        assert_eq!(date_from_days(19_726), "2024-01-04");
+        assert_eq!(today().len(), 10);
+        assert!(today().as_str() > "2024-01-01");
+    }
+
+    #[test]
+    fn falls_back_to_the_longest_prefix() {
+        let registry = ModelRegistry::new(default_models());
+        assert_eq!(registry.get("gpt-4-0613").unwrap().name, "gpt-4");
+        assert_eq!(registry.get("gpt-4-32k-0613").unwrap().name, "gpt-4-32k");
+        assert_eq!(registry.get("gpt-4o-2024-08-06").unwrap().name, "gpt-4o");
+        assert!(registry.get("gpt-40").is_none());
+        assert!(registry.get("unknown-model").is_none());
+    }
+
+    #[test]
+    fn resolves_deprecated_models() {
+        let mut registry = ModelRegistry::new(default_models());
+        assert_eq!(registry.resolve("text-davinci-003").unwrap().name, "gpt-3.5-turbo-instruct");
+        assert_eq!(registry.resolve("gpt-4").unwrap().name, "gpt-4");
+        assert!(registry.resolve("unknown-model").is_err());
+
+        registry.insert(ModelInfo { deprecation_date: Some("2000-01-01".to_string()), ..model("old-a", "old", 1_000, None, vec![ModelEndpoint::Chat], 0.0, 0.0) });
+        assert!(registry.resolve("old-a").unwrap_err().to_string().contains("no replacement"));
+    }
+
+    #[test]
+    fn detects_cyclic_replacements() {
+        let mut registry = ModelRegistry::new(default_models());
+        let deprecated = |name: &str, replacement: &str| ModelInfo {
+            deprecation_date: Some("2000-01-01".to_string()),
+            replacement: Some(replacement.to_string()),
+            ..model(name, "old", 1_000, None, vec![ModelEndpoint::Chat], 0.0, 0.0)
+        };
+        registry.insert(deprecated("old-a", "old-b"));
+        registry.insert(deprecated("old-b", "old-a"));
+        assert!(registry.resolve("old-a").unwrap_err().to_string().contains("cyclic"));
+    }
+}
@@ -0,0 +1,217 @@
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::pre_moderation::{PreModerator, PRE_MODERATOR, load_pre_moderator};
+use super::{cached_moderation, chunk_text, ModerationResult, ModerationSpan, ModerationVerdict, MODERATION_CHUNK_TOKENS, MODERATION_CHUNK_OVERLAP_TOKENS, MODERATION_MODEL};
+
+#[async_trait]
+pub trait ModerationBackend: Send + Sync {
+    fn name(&self) -> String;
+
+    async fn moderate(&self, text: &str) -> anyhow::Result<ModerationVerdict>;
+
+    // verdicts in input order, backends that can batch requests should override this
+    async fn moderate_batch(&self, texts: &[String]) -> anyhow::Result<Vec<ModerationVerdict>> {
+        let mut verdicts = Vec::new();
+        for text in texts {
+            verdicts.push(self.moderate(text).await?);
+        }
+        Ok(verdicts)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ModerationBackendConfig {
+    OpenAI,
+    // None uses the rules at PRE_MODERATION_RULES_PATH
+    LocalRules { rules_path: Option<String> },
+    Keywords { category: String, keywords: Vec<String> },
+    // backends run in order, see CompositeBackend
+    Composite(Vec<ModerationBackendConfig>),
+}
+
+impl Default for ModerationBackendConfig {
+    fn default() -> Self {
+        ModerationBackendConfig::Composite(vec![
+            ModerationBackendConfig::LocalRules { rules_path: None },
+            ModerationBackendConfig::OpenAI,
+        ])
+    }
+}
+
+pub fn build_moderation_backend(config: &ModerationBackendConfig) -> anyhow::Result<Box<dyn ModerationBackend>> {
+    Ok(match config {
+        ModerationBackendConfig::OpenAI => Box::new(OpenAIModerationBackend),
+        ModerationBackendConfig::LocalRules { rules_path: None } => Box::new(LocalRulesBackend::default()),
+        ModerationBackendConfig::LocalRules { rules_path: Some(path) } => Box::new(LocalRulesBackend::new(load_pre_moderator(path))),
+        ModerationBackendConfig::Keywords { category, keywords } => Box::new(KeywordBackend::new(category, keywords)?),
+        ModerationBackendConfig::Composite(configs) => Box::new(CompositeBackend::new(
+            configs.iter().map(build_moderation_backend).collect::<anyhow::Result<Vec<Box<dyn ModerationBackend>>>>()?
+        )),
+    })
+}
+
+// OpenAI's /v1/moderations, long texts are moderated in overlapping chunks
+pub struct OpenAIModerationBackend;
+
+#[async_trait]
+impl ModerationBackend for OpenAIModerationBackend {
+    fn name(&self) -> String {
+        "openai".to_string()
+    }
+
+    async fn moderate(&self, text: &str) -> anyhow::Result<ModerationVerdict> {
+        Ok(self.moderate_batch(&[text.to_string()]).await?.remove(0))
+    }
+
+    // the chunks of all texts go out in as few requests as possible
+    async fn moderate_batch(&self, texts: &[String]) -> anyhow::Result<Vec<ModerationVerdict>> {
+        let spans = texts.iter().map(|text| {
+            let mut spans = chunk_text(MODERATION_MODEL, text, MODERATION_CHUNK_TOKENS, MODERATION_CHUNK_OVERLAP_TOKENS);
+            if spans.is_empty() {
+                spans.push((0, text.len()));
+            }
+            spans
+        }).collect::<Vec<Vec<(usize, usize)>>>();
+        let inputs = texts.iter().zip(spans.iter())
+            .flat_map(|(text, spans)| spans.iter().map(|(start, end)| text[*start..*end].to_string()))
+            .collect::<Vec<String>>();
+        let mut results = cached_moderation(&inputs).await?.into_iter();
+        Ok(texts.iter().zip(spans).map(|(text, spans)| {
+            let chunks = results.by_ref().take(spans.len()).collect::<Vec<ModerationResult>>();
+            ModerationVerdict::from_chunks(text, spans, chunks)
+        }).collect())
+    }
+}
+
+// the local link screening and regex rules, no request leaves the process
+#[derive(Default)]
+pub struct LocalRulesBackend {
+    // None uses the shared PRE_MODERATOR
+    pre_moderator: Option<PreModerator>,
+}
+
+impl LocalRulesBackend {
+    pub fn new(pre_moderator: PreModerator) -> Self {
+        LocalRulesBackend { pre_moderator: Some(pre_moderator) }
+    }
+
+    fn pre_moderator(&self) -> &PreModerator {
+        self.pre_moderator.as_ref().unwrap_or(&PRE_MODERATOR)
+    }
+}
+
+#[async_trait]
+impl ModerationBackend for LocalRulesBackend {
+    fn name(&self) -> String {
+        "local-rules".to_string()
+    }
+
+    async fn moderate(&self, text: &str) -> anyhow::Result<ModerationVerdict> {
+        let local = self.pre_moderator().pre_moderate(text);
+        Ok(ModerationVerdict::from_chunks(text, Vec::new(), Vec::new()).with_local(text, local))
+    }
+}
+
+// flags texts containing any of the keywords as whole words, ignoring case
+pub struct KeywordBackend {
+    category: String,
+    regex: Option<Regex>,
+}
+
+impl KeywordBackend {
+    pub fn new(category: &str, keywords: &[String]) -> anyhow::Result<Self> {
+        let keywords = keywords.iter().filter(|x| !x.trim().is_empty()).map(|x| regex::escape(x.trim())).collect::<Vec<String>>();
+        let regex = if keywords.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&format!(r"(?i)\b(?:{})\b", keywords.join("|")))?)
+        };
+        Ok(KeywordBackend { category: category.to_string(), regex })
+    }
+}
+
+#[async_trait]
+impl ModerationBackend for KeywordBackend {
+    fn name(&self) -> String {
+        format!("keywords:{}", self.category)
+    }
+
+    async fn moderate(&self, text: &str) -> anyhow::Result<ModerationVerdict> {
+        let found = self.regex.as_ref().and_then(|x| x.find(text));
+        let score = if found.is_some() { 1.0 } else { 0.0 };
+        let result = ModerationResult::new(vec![(self.category.to_owned(), found.is_some(), score)]);
+        let mut verdict = ModerationVerdict::from_chunks(text, vec![(0, text.len())], vec![result]);
+        verdict.worst_span = found.map(|x| ModerationSpan {
+            start: x.start(),
+            end: x.end(),
+            text: x.as_str().to_string(),
+            category: self.category.to_owned(),
+            score,
+        });
+        Ok(verdict)
+    }
+}
+
+// runs the backends in order and combines their verdicts,
+// later backends see the text redacted by earlier ones and are skipped once the text is flagged
+pub struct CompositeBackend {
+    backends: Vec<Box<dyn ModerationBackend>>,
+}
+
+impl CompositeBackend {
+    pub fn new(backends: Vec<Box<dyn ModerationBackend>>) -> Self {
+        CompositeBackend { backends }
+    }
+}
+
+fn combine(verdict: ModerationVerdict, other: ModerationVerdict) -> ModerationVerdict {
+    let worst_span = match (verdict.worst_span, other.worst_span) {
+        (Some(a), Some(b)) => Some(if b.score > a.score { b } else { a }),
+        (a, b) => a.or(b),
+    };
+    // redactions of later backends apply to the already redacted text
+    let local = match (verdict.local, other.local) {
+        (Some(mut a), Some(b)) => {
+            a.links.extend(b.links);
+            a.rule_hits.extend(b.rule_hits);
+            a.action = a.action.max(b.action);
+            a.redacted_text = b.redacted_text;
+            Some(a)
+        }
+        (a, b) => a.or(b),
+    };
+    ModerationVerdict {
+        result: ModerationResult::aggregate(&[verdict.result, other.result]),
+        chunks: verdict.chunks.into_iter().chain(other.chunks).collect(),
+        spans: verdict.spans.into_iter().chain(other.spans).collect(),
+        worst_span,
+        local,
+    }
+}
+
+#[async_trait]
+impl ModerationBackend for CompositeBackend {
+    fn name(&self) -> String {
+        format!("composite({})", self.backends.iter().map(|x| x.name()).collect::<Vec<String>>().join(", "))
+    }
+
+    async fn moderate(&self, text: &str) -> anyhow::Result<ModerationVerdict> {
+        let mut verdict: Option<ModerationVerdict> = None;
+        for backend in self.backends.iter() {
+            let input = verdict.as_ref().and_then(|x| x.redacted_text()).unwrap_or(text).to_string();
+            let backend_verdict = backend.moderate(&input).await?;
+            let combined = match verdict {
+                Some(verdict) => combine(verdict, backend_verdict),
+                None => backend_verdict,
+            };
+            let flagged = combined.result.flagged;
+            verdict = Some(combined);
+            if flagged {
+                break;
+            }
+        }
+        Ok(verdict.unwrap_or_else(|| ModerationVerdict::from_chunks(text, Vec::new(), Vec::new())))
+    }
+}
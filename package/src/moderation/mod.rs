@@ -1,73 +1,562 @@
 
-use reqwest::Client;
-use reqwest::header::HeaderValue;
-use reqwest::header::CONTENT_TYPE;
+use std::collections::{BTreeMap, BTreeSet};
+
+use lazy_static::lazy_static;
+
+use crate::api::post_json;
+use crate::tokenizer::{count_tokens, truncate_to_tokens};
+use crate::cache::{digest, HashValueStore, SLED_DB};
+use crate::budget::ClientIdentity;
+use crate::pre_moderation::{PreModerationAction, PreModerationVerdict};
+use rust_openai_gpt_tools_socket_ipc::ipc::OpenAIGPTModerationMode;
+
+pub mod backend;
+
+use backend::{build_moderation_backend, ModerationBackend, ModerationBackendConfig};
+
+pub const MODERATION_MODEL: &str = "text-moderation-latest";
+pub const MODERATION_BATCH_SIZE: usize = 32;
+pub const MODERATION_CHUNK_TOKENS: usize = 2_000;
+pub const MODERATION_CHUNK_OVERLAP_TOKENS: usize = 100;
+
+pub const MODERATION_POLICY_PATH: &str = "./tmp/rust_openai_gpt_tools_moderation_policy.json";
+
+lazy_static!{
+   pub static ref MODERATION_POLICY: ModerationPolicy = load_moderation_policy(MODERATION_POLICY_PATH);
+   pub static ref MODERATION_BACKEND: Box<dyn ModerationBackend> = load_moderation_backend(&MODERATION_POLICY.backend);
+   static ref MODERATION_RESULT_STORE: HashValueStore = HashValueStore::open_tree(&SLED_DB, "moderation_results").unwrap();
+}
 
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Moderation {
-    id: String,
-    model: String,
+    pub id: String,
+    pub model: String,
     pub results: Vec<ModerationResult>,
 }
 
+pub const KNOWN_CATEGORIES: [&str; 13] = [
+    "harassment",
+    "harassment/threatening",
+    "hate",
+    "hate/threatening",
+    "illicit",
+    "illicit/violent",
+    "self-harm",
+    "self-harm/instructions",
+    "self-harm/intent",
+    "sexual",
+    "sexual/minors",
+    "violence",
+    "violence/graphic",
+];
+
+// categories are kept by name so new ones are preserved, newer models report null for categories they do not cover
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct ModerationResult {
-    categories: Categories,
-    category_scores: CategoryScores,
+    categories: BTreeMap<String, Option<bool>>,
+    category_scores: BTreeMap<String, Option<f32>>,
     pub flagged: bool,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-struct Categories {
-    hate: bool,
-    #[serde(rename = "hate/threatening")]
-    hate_threatening: bool,
-    #[serde(rename = "self-harm")]
-    self_harm: bool,
-    sexual: bool,
-    #[serde(rename = "sexual/minors")]
-    sexual_minors: bool,
-    violence: bool,
-    #[serde(rename = "violence/graphic")]
-    violence_graphic: bool,
+impl TryFrom<Vec<u8>> for ModerationResult {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(bincode::deserialize(&item[..])?)
+    }
+}
+
+impl TryFrom<ModerationResult> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: ModerationResult) -> anyhow::Result<Self> {
+        Ok(bincode::serialize(&item)?)
+    }
+}
+
+impl ModerationResult {
+    // (category, flagged, score), flagged if any category is
+    pub fn new(categories: Vec<(String, bool, f32)>) -> ModerationResult {
+        ModerationResult {
+            flagged: categories.iter().any(|x| x.1),
+            categories: categories.iter().map(|(category, flagged, _)| (category.to_owned(), Some(*flagged))).collect(),
+            category_scores: categories.into_iter().map(|(category, _, score)| (category, Some(score))).collect(),
+        }
+    }
+
+    pub fn categories(&self) -> BTreeMap<String, bool> {
+        self.categories.iter().filter_map(|(k, v)| v.map(|v| (k.to_owned(), v))).collect()
+    }
+
+    pub fn category_scores(&self) -> BTreeMap<String, f32> {
+        self.category_scores.iter().filter_map(|(k, v)| v.map(|v| (k.to_owned(), v))).collect()
+    }
+
+    pub fn is_flagged(&self, category: &str) -> bool {
+        self.categories.get(category).copied().flatten().unwrap_or(false)
+    }
+
+    pub fn score(&self, category: &str) -> f32 {
+        self.category_scores.get(category).copied().flatten().unwrap_or(0.0)
+    }
+
+    // categories OpenAI reported that this crate does not know about
+    pub fn unknown_categories(&self) -> Vec<String> {
+        self.categories.keys().chain(self.category_scores.keys())
+            .filter(|x| !KNOWN_CATEGORIES.contains(&x.as_str()))
+            .cloned()
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct ModerationViolation {
+    pub category: String,
+    pub score: f32,
+    // None if the category was rejected because OpenAI flagged it
+    pub threshold: Option<f32>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
-struct CategoryScores {
-    hate: f32,
-    #[serde(rename = "hate/threatening")]
-    hate_threatening: f32,
-    #[serde(rename = "self-harm")]
-    self_harm: f32,
-    sexual: f32,
-    #[serde(rename = "sexual/minors")]
-    sexual_minors: f32,
-    violence: f32,
-    #[serde(rename = "violence/graphic")]
-    violence_graphic: f32,
+pub struct ModerationPolicy {
+    #[serde(default)]
+    pub thresholds: BTreeMap<String, f32>,
+    // for categories without a threshold, if None OpenAI's own flag is used
+    #[serde(default)]
+    pub default_threshold: Option<f32>,
+    // return rejected completions to the client with the offending sentences masked
+    #[serde(default)]
+    pub include_redacted_result: bool,
+    #[serde(default = "default_moderation_mode")]
+    pub default_mode: OpenAIGPTModerationMode,
+    // the lowest mode a client may request, keyed by the verified client id,
+    // unverified clients and clients that are not listed can not go below the default mode
+    #[serde(default)]
+    pub client_minimum_modes: BTreeMap<String, OpenAIGPTModerationMode>,
+    // the moderation stack used by moderate_text, local rules followed by OpenAI by default
+    #[serde(default)]
+    pub backend: ModerationBackendConfig,
 }
 
+fn default_moderation_mode() -> OpenAIGPTModerationMode {
+    OpenAIGPTModerationMode::Both
+}
 
-pub async fn moderation_endpoint(prompt: &str) -> anyhow::Result<Moderation> {
+impl Default for ModerationPolicy {
+    fn default() -> Self {
+        ModerationPolicy {
+            thresholds: BTreeMap::new(),
+            default_threshold: None,
+            include_redacted_result: false,
+            default_mode: default_moderation_mode(),
+            client_minimum_modes: BTreeMap::new(),
+            backend: ModerationBackendConfig::default(),
+        }
+    }
+}
+
+impl ModerationPolicy {
+    pub fn threshold(&self, category: &str) -> Option<f32> {
+        self.thresholds.get(category).copied().or(self.default_threshold)
+    }
+
+    pub fn violations(&self, result: &ModerationResult) -> Vec<ModerationViolation> {
+        let categories = result.categories.keys().chain(result.category_scores.keys()).collect::<BTreeSet<&String>>();
+        categories.into_iter().filter_map(|category| {
+            let score = result.score(category);
+            let threshold = self.threshold(category);
+            let violated = match threshold {
+                Some(threshold) => score >= threshold,
+                None => result.is_flagged(category),
+            };
+            if violated {
+                Some(ModerationViolation { category: category.to_owned(), score, threshold })
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    pub fn effective_mode(&self, client: &ClientIdentity, requested: Option<OpenAIGPTModerationMode>) -> OpenAIGPTModerationMode {
+        let minimum = Some(client).filter(|x| x.verified)
+            .and_then(|x| self.client_minimum_modes.get(&x.client_id).copied())
+            .unwrap_or(self.default_mode);
+        requested.unwrap_or(self.default_mode).union(minimum)
+    }
+
+    pub fn is_unsafe(&self, moderation: &Moderation) -> bool {
+        moderation.results.iter().any(|x| !self.violations(x).is_empty())
+    }
+}
+
+pub fn load_moderation_backend(config: &ModerationBackendConfig) -> Box<dyn ModerationBackend> {
+    match build_moderation_backend(config) {
+        Ok(backend) => backend,
+        Err(err) => {
+            println!("Error: invalid moderation backend {:?}: {}, using defaults", config, err);
+            build_moderation_backend(&ModerationBackendConfig::default()).unwrap()
+        }
+    }
+}
+
+pub fn load_moderation_policy(path: &str) -> ModerationPolicy {
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<ModerationPolicy>(&json) {
+            Ok(policy) => policy,
+            Err(err) => {
+                println!("Error: invalid moderation policy at '{}': {}, using defaults", path, err);
+                ModerationPolicy::default()
+            }
+        },
+        Err(_) => ModerationPolicy::default(),
+    }
+}
+
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationSide {
+    Prompt,
+    Result,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModerationRejection {
+    pub endpoint: String,
+    pub side: ModerationSide,
+    pub violations: Vec<ModerationViolation>,
+    pub worst_span: Option<ModerationSpan>,
+    pub redacted_result: Option<String>,
+}
+
+impl std::fmt::Display for ModerationRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let side = match self.side {
+            ModerationSide::Prompt => "prompt",
+            ModerationSide::Result => "result",
+        };
+        let categories = self.violations.iter().map(|x| format!("{} ({:.3})", x.category, x.score)).collect::<Vec<String>>();
+        write!(f, "Error: {} {} unsafe! {}", self.endpoint, side, categories.join(", "))
+    }
+}
+
+impl std::error::Error for ModerationRejection {}
+
+impl ModerationPolicy {
+    pub fn rejection(&self, endpoint: &str, side: ModerationSide, verdict: &ModerationVerdict) -> Option<ModerationRejection> {
+        let violations = self.violations(&verdict.result);
+        if violations.is_empty() {
+            None
+        } else {
+            Some(ModerationRejection { endpoint: endpoint.to_string(), side, violations, worst_span: verdict.worst_span.clone(), redacted_result: None })
+        }
+    }
+}
+
+// byte ranges of the sentences, a sentence ends after '.', '!' or '?' followed by whitespace, or at a line break
+pub fn split_sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next_is_whitespace = chars.peek().map(|(_, x)| x.is_whitespace()).unwrap_or(true);
+        if c == '\n' || (matches!(c, '.' | '!' | '?') && next_is_whitespace) {
+            let end = i + c.len_utf8();
+            if !text[start..end].trim().is_empty() {
+                sentences.push((start, end));
+            }
+            start = end;
+        }
+    }
+    if !text[start..].trim().is_empty() {
+        sentences.push((start, text.len()));
+    }
+    sentences
+}
+
+// masks the sentences that violate the policy
+pub async fn redact(text: &str, policy: &ModerationPolicy, backend: &dyn ModerationBackend) -> anyhow::Result<String> {
+    let sentences = split_sentences(text);
+    let inputs = sentences.iter().map(|(start, end)| text[*start..*end].to_string()).collect::<Vec<String>>();
+    let verdicts = backend.moderate_batch(&inputs).await?;
+
+    let mut redacted = String::new();
+    let mut position = 0;
+    for ((start, end), verdict) in sentences.iter().zip(verdicts.iter()) {
+        redacted.push_str(&text[position..*start]);
+        if policy.violations(&verdict.result).is_empty() {
+            redacted.push_str(&text[*start..*end]);
+        } else {
+            let leading_whitespace = text[*start..*end].len() - text[*start..*end].trim_start().len();
+            redacted.push_str(&text[*start..*start + leading_whitespace]);
+            redacted.push_str("[redacted]");
+        }
+        position = *end;
+    }
+    redacted.push_str(&text[position..]);
+    Ok(redacted)
+}
+
+async fn moderation_request(input: serde_json::Value, estimated_tokens: u64) -> anyhow::Result<Moderation> {
 
     let json_data = serde_json::json!({
-                "input": prompt,
+                "input": input,
               });
 
     // println!("{:?}",&json_data);
 
-    let client = Client::new();
-    let url = "https://api.openai.com/v1/moderations";
-    let response = client.post(url)
-        .bearer_auth(&super::ENV.openai_api_key)
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(json_data.to_string())
-        .send().await;
-
-    let moderation = response?.json::<Moderation>().await?;
+    let moderation = post_json::<Moderation>("https://api.openai.com/v1/moderations", MODERATION_MODEL, estimated_tokens, &json_data).await?;
 
-    // println!("Moderation: {:?}",moderation);
     Ok(moderation)
 }
 
+pub async fn moderation_endpoint(prompt: &str) -> anyhow::Result<Moderation> {
+    moderation_request(serde_json::json!(prompt), count_tokens(MODERATION_MODEL, prompt) as u64).await
+}
+
+// moderates many texts, split into requests of at most MODERATION_BATCH_SIZE inputs, results are in input order
+pub async fn moderation_batch_endpoint(inputs: &[String]) -> anyhow::Result<Moderation> {
+    let mut moderation: Option<Moderation> = None;
+    for batch in inputs.chunks(MODERATION_BATCH_SIZE) {
+        let mut batch_moderation = moderation_request(serde_json::json!(batch), batch.iter().map(|x| count_tokens(MODERATION_MODEL, x) as u64).sum()).await?;
+        if batch_moderation.results.len() != batch.len() {
+            return Err(anyhow::anyhow!("Error: expected {} moderation results, got {}", batch.len(), batch_moderation.results.len()));
+        }
+        match moderation.as_mut() {
+            Some(moderation) => moderation.results.append(&mut batch_moderation.results),
+            None => moderation = Some(batch_moderation),
+        }
+    }
+    moderation.ok_or_else(|| anyhow::anyhow!("Error: nothing to moderate"))
+}
+
+// byte ranges of chunks of at most `max_tokens` of `model_name`, cut at sentence boundaries and overlapping by about `overlap_tokens`
+pub fn chunk_text(model_name: &str, text: &str, max_tokens: usize, overlap_tokens: usize) -> Vec<(usize, usize)> {
+    let mut pieces: Vec<(usize, usize, usize)> = Vec::new();
+    for (start, end) in split_sentences(text) {
+        let mut start = start;
+        // sentences that do not fit into one chunk are cut by tokens
+        while start < end {
+            let rest = &text[start..end];
+            let piece = truncate_to_tokens(model_name, rest, max_tokens.max(1));
+            let piece_len = if piece.is_empty() { rest.chars().next().map(|x| x.len_utf8()).unwrap_or(rest.len()) } else { piece.len() };
+            pieces.push((start, start + piece_len, count_tokens(model_name, &text[start..start + piece_len])));
+            start += piece_len;
+        }
+    }
+
+    let mut chunks = Vec::new();
+    let mut i = 0;
+    while i < pieces.len() {
+        let mut j = i;
+        let mut tokens = 0;
+        while j < pieces.len() && (j == i || tokens + pieces[j].2 <= max_tokens) {
+            tokens += pieces[j].2;
+            j += 1;
+        }
+        chunks.push((pieces[i].0, pieces[j - 1].1));
+        if j == pieces.len() {
+            break;
+        }
+        let mut next = j;
+        let mut overlap = 0;
+        while next - 1 > i && overlap + pieces[next - 1].2 <= overlap_tokens {
+            overlap += pieces[next - 1].2;
+            next -= 1;
+        }
+        i = next;
+    }
+    chunks
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
+pub struct ModerationSpan {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub category: String,
+    pub score: f32,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ModerationVerdict {
+    // highest score and any flag of each category across all chunks
+    pub result: ModerationResult,
+    pub chunks: Vec<ModerationResult>,
+    pub spans: Vec<(usize, usize)>,
+    pub worst_span: Option<ModerationSpan>,
+    // links and rule hits found before the text was sent to OpenAI
+    pub local: Option<PreModerationVerdict>,
+}
+
+impl ModerationResult {
+    pub fn aggregate(results: &[ModerationResult]) -> ModerationResult {
+        let mut categories: BTreeMap<String, Option<bool>> = BTreeMap::new();
+        let mut category_scores: BTreeMap<String, Option<f32>> = BTreeMap::new();
+        for result in results {
+            for (category, flagged) in result.categories.iter() {
+                let entry = categories.entry(category.to_owned()).or_insert(None);
+                *entry = match (*entry, *flagged) {
+                    (Some(a), Some(b)) => Some(a || b),
+                    (a, b) => a.or(b),
+                };
+            }
+            for (category, score) in result.category_scores.iter() {
+                let entry = category_scores.entry(category.to_owned()).or_insert(None);
+                *entry = match (*entry, *score) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+        }
+        ModerationResult {
+            categories,
+            category_scores,
+            flagged: results.iter().any(|x| x.flagged),
+        }
+    }
+
+    // local findings as categories, only rejected ones are flagged
+    pub fn local(verdict: &PreModerationVerdict) -> ModerationResult {
+        let mut categories = BTreeMap::new();
+        let mut category_scores = BTreeMap::new();
+        for (category, action) in verdict.categories() {
+            let rejected = action == PreModerationAction::Reject;
+            categories.insert(category.to_owned(), Some(rejected));
+            category_scores.insert(category, Some(if rejected { 1.0 } else { 0.0 }));
+        }
+        ModerationResult {
+            categories,
+            category_scores,
+            flagged: verdict.action == PreModerationAction::Reject,
+        }
+    }
+
+    // the category with the highest score
+    pub fn worst_category(&self) -> Option<(String, f32)> {
+        self.category_scores().into_iter().max_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+impl ModerationVerdict {
+    pub fn from_chunks(text: &str, spans: Vec<(usize, usize)>, chunks: Vec<ModerationResult>) -> Self {
+        let worst_span = spans.iter().zip(chunks.iter())
+            .filter_map(|((start, end), result)| result.worst_category().map(|(category, score)| ModerationSpan {
+                start: *start,
+                end: *end,
+                text: text[*start..*end].to_string(),
+                category,
+                score,
+            }))
+            .max_by(|a, b| a.score.total_cmp(&b.score));
+        ModerationVerdict {
+            result: ModerationResult::aggregate(&chunks),
+            chunks,
+            spans,
+            worst_span,
+            local: None,
+        }
+    }
+
+    // the text with the redacted links and rule matches, if the local rules asked for it
+    pub fn redacted_text(&self) -> Option<&str> {
+        self.local.as_ref().filter(|x| x.action == PreModerationAction::Redact).map(|x| x.redacted_text.as_str())
+    }
+
+    fn with_local(mut self, text: &str, local: PreModerationVerdict) -> Self {
+        let local_result = ModerationResult::local(&local);
+        if local.action == PreModerationAction::Reject {
+            let rejected = local.links.iter().filter(|x| x.action == PreModerationAction::Reject).map(|x| (x.start, x.end))
+                .chain(local.rule_hits.iter().filter(|x| x.action == PreModerationAction::Reject).map(|x| (x.start, x.end)))
+                .next();
+            if let Some((start, end)) = rejected {
+                let category = local_result.categories().into_iter().find(|(_, flagged)| *flagged).map(|(x, _)| x).unwrap_or_default();
+                self.worst_span = Some(ModerationSpan { start, end, text: text[start..end].to_string(), category, score: 1.0 });
+            }
+        }
+        self.result = ModerationResult::aggregate(&[self.result, local_result]);
+        self.local = Some(local);
+        self
+    }
+}
+
+// moderates the text with the configured backend
+pub async fn moderate_text(text: &str) -> anyhow::Result<ModerationVerdict> {
+    MODERATION_BACKEND.moderate(text).await
+}
+
+// like moderation_batch_endpoint, but only texts that were not moderated before are sent to OpenAI
+pub async fn cached_moderation(inputs: &[String]) -> anyhow::Result<Vec<ModerationResult>> {
+    let hashes = inputs.iter().map(|x| digest(&(MODERATION_MODEL, x))).collect::<Vec<u64>>();
+    let mut results: Vec<Option<ModerationResult>> = Vec::new();
+    for hash in hashes.iter() {
+        results.push(MODERATION_RESULT_STORE.get_item_by_hash::<ModerationResult>(*hash).unwrap_or(None));
+    }
+
+    let missing = results.iter().enumerate().filter(|(_, x)| x.is_none()).map(|(i, _)| i).collect::<Vec<usize>>();
+    if !missing.is_empty() {
+        let missing_inputs = missing.iter().map(|i| inputs[*i].to_owned()).collect::<Vec<String>>();
+        let moderation = moderation_batch_endpoint(&missing_inputs).await?;
+        for (i, result) in missing.into_iter().zip(moderation.results) {
+            MODERATION_RESULT_STORE.insert_item(hashes[i], result.clone()).ok();
+            results[i] = Some(result);
+        }
+    }
+    Ok(results.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> usize {
+        count_tokens(MODERATION_MODEL, text)
+    }
+
+    #[test]
+    fn split_sentences_keeps_punctuation_and_newlines() {
+        let text = "First one. Second one!\nThird v1.2 here? Last";
+        let sentences = split_sentences(text).into_iter().map(|(start, end)| &text[start..end]).collect::<Vec<&str>>();
+        // the newline after "!" would be a sentence of its own, but blank sentences are dropped
+        assert_eq!(sentences, vec!["First one.", " Second one!", "Third v1.2 here?", " Last"]);
+    }
+
+    #[test]
+    fn chunk_text_cuts_at_sentences_within_the_limit() {
+        let text = (0..40).map(|i| format!("Sentence number {} is here.", i)).collect::<Vec<String>>().join(" ");
+        let max_tokens = 4 * tokens(" Sentence number 10 is here.");
+        let chunks = chunk_text(MODERATION_MODEL, &text, max_tokens, 0);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.first().unwrap().0, 0);
+        assert_eq!(chunks.last().unwrap().1, text.len());
+        for window in chunks.windows(2) {
+            // without overlap the next chunk starts where the previous one ended
+            assert_eq!(window[0].1, window[1].0);
+        }
+        for (start, end) in chunks.iter() {
+            assert!(tokens(&text[*start..*end]) <= max_tokens);
+            assert!(text[*start..*end].ends_with('.'));
+        }
+    }
+
+    #[test]
+    fn chunk_text_overlaps_and_cuts_long_sentences() {
+        let text = (0..40).map(|i| format!("Sentence number {} is here.", i)).collect::<Vec<String>>().join(" ");
+        let sentence_tokens = tokens(" Sentence number 10 is here.");
+        let chunks = chunk_text(MODERATION_MODEL, &text, 4 * sentence_tokens, sentence_tokens);
+        for window in chunks.windows(2) {
+            assert!(window[1].0 < window[0].1);
+            assert!(window[1].0 > window[0].0);
+        }
+        assert_eq!(chunks.last().unwrap().1, text.len());
+
+        // a single sentence longer than the limit is cut by tokens, non-ASCII boundaries included
+        let long = "wörd ".repeat(200);
+        let chunks = chunk_text(MODERATION_MODEL, &long, 10, 0);
+        assert!(chunks.len() > 1);
+        for (start, end) in chunks.iter() {
+            assert!(tokens(&long[*start..*end]) <= 10);
+        }
+        assert_eq!(chunks.iter().map(|(start, end)| &long[*start..*end]).collect::<String>(), long);
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTEmbeddingRequest, OpenAIGPTRequest, OpenAIGPTResult};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+
+use crate::budget::ClientIdentity;
+use crate::cache::{digest, HashValueStore, SLED_DB};
+use crate::prompt::PromptTemplate;
+use crate::service::process_request;
+
+lazy_static!{
+   static ref PIPELINE_STEP_STORE: HashValueStore = HashValueStore::open_tree(&SLED_DB, "pipeline_steps").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PipelineValue {
+    Text(String),
+    Embeddings(Vec<Vec<f32>>),
+    Json(serde_json::Value),
+}
+
+impl PipelineValue {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            PipelineValue::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    // what a template sees of the value
+    fn to_variable(&self) -> anyhow::Result<String> {
+        match self {
+            PipelineValue::Text(text) => Ok(text.to_owned()),
+            PipelineValue::Json(json) => Ok(json.to_string()),
+            PipelineValue::Embeddings(_) => Err(anyhow::anyhow!("Error: embeddings can not be used in a prompt")),
+        }
+    }
+}
+
+// stored as JSON, bincode can not deserialize serde_json::Value
+impl TryFrom<Vec<u8>> for PipelineValue {
+    type Error = anyhow::Error;
+    fn try_from(item: Vec<u8>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&item[..])?)
+    }
+}
+
+impl TryFrom<PipelineValue> for Vec<u8> {
+    type Error = anyhow::Error;
+    fn try_from(item: PipelineValue) -> anyhow::Result<Self> {
+        Ok(serde_json::to_vec(&item)?)
+    }
+}
+
+type StepFunction = Arc<dyn Fn(&BTreeMap<String, PipelineValue>) -> anyhow::Result<PipelineValue> + Send + Sync>;
+
+#[derive(Clone)]
+pub enum StepKind {
+    // the template's variables name the steps or pipeline inputs it reads
+    Chat { model_name: String, template: PromptTemplate, completion_token_limit: u16 },
+    Text { model_name: String, template: PromptTemplate, completion_token_limit: u16 },
+    // one embedding per input, in order
+    Embedding { model_name: String },
+    // closures can not be hashed, bump the version when the function changes to invalidate its cached results
+    Function { version: u32, function: StepFunction },
+}
+
+impl StepKind {
+    // identifies what the step does for the cache
+    fn fingerprint(&self) -> String {
+        match self {
+            StepKind::Chat { model_name, template, completion_token_limit } => format!("chat/{}/{}/{:?}/{}/{}", model_name, completion_token_limit, template.system, template.template, template.stop.as_ref().map(|x| x.join("|")).unwrap_or_default()),
+            StepKind::Text { model_name, template, completion_token_limit } => format!("text/{}/{}/{:?}/{}/{}", model_name, completion_token_limit, template.system, template.template, template.stop.as_ref().map(|x| x.join("|")).unwrap_or_default()),
+            StepKind::Embedding { model_name } => format!("embedding/{}", model_name),
+            StepKind::Function { version, .. } => format!("function/{}", version),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PipelineStep {
+    pub name: String,
+    pub inputs: Vec<String>,
+    pub kind: StepKind,
+}
+
+impl PipelineStep {
+    async fn run(&self, client: &ClientIdentity, inputs: &BTreeMap<String, PipelineValue>) -> anyhow::Result<PipelineValue> {
+        match &self.kind {
+            StepKind::Chat { model_name, template, completion_token_limit } | StepKind::Text { model_name, template, completion_token_limit } => {
+                let variables = inputs.iter().map(|(name, value)| Ok((name.as_str(), value.to_variable()?))).collect::<anyhow::Result<Vec<(&str, String)>>>()?;
+                let rendered = template.render(&variables.iter().map(|(name, value)| (*name, value.as_str())).collect())?;
+                let text = if let StepKind::Chat { .. } = self.kind {
+                    rendered.chat_completion(client, model_name, *completion_token_limit).await?
+                } else {
+                    rendered.text_completion(client, model_name, *completion_token_limit).await?
+                };
+                Ok(PipelineValue::Text(text.trim().to_string()))
+            }
+            StepKind::Embedding { model_name } => {
+                let texts = self.inputs.iter().map(|x| inputs[x].to_variable()).collect::<anyhow::Result<Vec<String>>>()?;
+                let request = OpenAIGPTRequest::EmbeddingRequest(OpenAIGPTEmbeddingRequest {
+                    texts,
+                    model_name: Some(model_name.to_owned()),
+                    dimensions: None,
+                    encoding_format: None,
+                });
+                match process_request(client, request).await? {
+                    OpenAIGPTResult::EmbeddingResult(result) => Ok(PipelineValue::Embeddings(result.result)),
+                    result => Err(anyhow::anyhow!("Error: unexpected result {:?}", result)),
+                }
+            }
+            StepKind::Function { function, .. } => function(inputs),
+        }
+    }
+
+    // results are cached per client, and the order of the inputs matters, e.g. for the embeddings they give
+    fn cache_key(&self, client: &ClientIdentity, pipeline: &str, inputs: &BTreeMap<String, PipelineValue>) -> anyhow::Result<u64> {
+        Ok(digest(&(client.client_id.as_str(), client.project.as_deref(), pipeline, self.name.as_str(), self.kind.fingerprint(), &self.inputs, bincode::serialize(inputs)?)))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StepReport {
+    pub name: String,
+    pub cached: bool,
+    // None if the step ran or came from the cache
+    pub error: Option<String>,
+    // steps are skipped when an input failed
+    pub skipped: bool,
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PipelineRun {
+    // the pipeline inputs and the output of every step that succeeded
+    pub values: BTreeMap<String, PipelineValue>,
+    // in order of completion
+    pub steps: Vec<StepReport>,
+}
+
+impl PipelineRun {
+    pub fn get(&self, name: &str) -> Option<&PipelineValue> {
+        self.values.get(name)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.steps.iter().all(|x| x.error.is_none() && !x.skipped)
+    }
+}
+
+// steps read the pipeline inputs and the outputs of earlier steps by name
+pub struct Pipeline {
+    name: String,
+    steps: Vec<PipelineStep>,
+    concurrency: usize,
+}
+
+impl Pipeline {
+    // the name separates the cached results of different pipelines
+    pub fn new(name: &str) -> Self {
+        Pipeline { name: name.to_string(), steps: Vec::new(), concurrency: 4 }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    pub fn step(mut self, name: &str, inputs: Vec<String>, kind: StepKind) -> Self {
+        self.steps.push(PipelineStep { name: name.to_string(), inputs, kind });
+        self
+    }
+
+    // the inputs are the template's variables
+    pub fn chat(self, name: &str, model_name: &str, template: PromptTemplate, completion_token_limit: u16) -> anyhow::Result<Self> {
+        let inputs = template.variables()?;
+        Ok(self.step(name, inputs, StepKind::Chat { model_name: model_name.to_string(), template, completion_token_limit }))
+    }
+
+    pub fn text(self, name: &str, model_name: &str, template: PromptTemplate, completion_token_limit: u16) -> anyhow::Result<Self> {
+        let inputs = template.variables()?;
+        Ok(self.step(name, inputs, StepKind::Text { model_name: model_name.to_string(), template, completion_token_limit }))
+    }
+
+    pub fn embedding(self, name: &str, model_name: &str, inputs: &[&str]) -> Self {
+        self.step(name, inputs.iter().map(|x| x.to_string()).collect(), StepKind::Embedding { model_name: model_name.to_string() })
+    }
+
+    pub fn function<F>(self, name: &str, inputs: &[&str], version: u32, function: F) -> Self
+    where F: Fn(&BTreeMap<String, PipelineValue>) -> anyhow::Result<PipelineValue> + Send + Sync + 'static {
+        self.step(name, inputs.iter().map(|x| x.to_string()).collect(), StepKind::Function { version, function: Arc::new(function) })
+    }
+
+    pub fn steps(&self) -> &[PipelineStep] {
+        &self.steps
+    }
+
+    // every input is a pipeline input or a step, step names are unique and there are no cycles
+    pub fn validate(&self, input_names: &BTreeSet<String>) -> anyhow::Result<()> {
+        let mut known = input_names.clone();
+        let mut pending = self.steps.iter().collect::<Vec<&PipelineStep>>();
+        for step in self.steps.iter() {
+            if input_names.contains(&step.name) || self.steps.iter().filter(|x| x.name == step.name).count() > 1 {
+                return Err(anyhow::anyhow!("Error: step name '{}' of pipeline '{}' is not unique", step.name, self.name));
+            }
+        }
+        while !pending.is_empty() {
+            let (ready, blocked): (Vec<&PipelineStep>, Vec<&PipelineStep>) = pending.into_iter().partition(|x| x.inputs.iter().all(|x| known.contains(x)));
+            if ready.is_empty() {
+                let missing = blocked.iter().flat_map(|x| x.inputs.iter()).filter(|x| !known.contains(*x) && !blocked.iter().any(|y| &y.name == *x)).collect::<Vec<&String>>();
+                return match missing.first() {
+                    Some(name) => Err(anyhow::anyhow!("Error: unknown input '{}' in pipeline '{}'", name, self.name)),
+                    None => Err(anyhow::anyhow!("Error: pipeline '{}' has a cycle between {:?}", self.name, blocked.iter().map(|x| &x.name).collect::<Vec<&String>>())),
+                };
+            }
+            known.extend(ready.iter().map(|x| x.name.to_owned()));
+            pending = blocked;
+        }
+        Ok(())
+    }
+
+    // runs every step as soon as its inputs are there, at most `concurrency` at a time,
+    // results are cached per step and input values, so a rerun only repeats what failed
+    // completions and embeddings go through the service and are paid from the client's budget
+    pub async fn run(&self, client: &ClientIdentity, inputs: BTreeMap<String, PipelineValue>) -> anyhow::Result<PipelineRun> {
+        self.validate(&inputs.keys().cloned().collect())?;
+        let mut values = inputs;
+        let mut reports = Vec::new();
+        let mut pending = self.steps.clone();
+        let mut failed: BTreeSet<String> = BTreeSet::new();
+        let mut running = JoinSet::new();
+
+        loop {
+            // steps with a failed input never run, neither do the steps that need them
+            while let Some(skipped) = pending.iter().position(|x| x.inputs.iter().any(|x| failed.contains(x))) {
+                let step = pending.remove(skipped);
+                failed.insert(step.name.to_owned());
+                reports.push(StepReport { name: step.name, cached: false, error: None, skipped: true, duration_ms: 0 });
+            }
+
+            while running.len() < self.concurrency {
+                let next = match pending.iter().position(|x| x.inputs.iter().all(|x| values.contains_key(x))) {
+                    Some(next) => pending.remove(next),
+                    None => break,
+                };
+                let step_inputs = next.inputs.iter().map(|x| (x.to_owned(), values[x].clone())).collect::<BTreeMap<String, PipelineValue>>();
+                let (pipeline, client, name) = (self.name.to_owned(), client.clone(), next.name.to_owned());
+                running.spawn(async move {
+                    let started = Instant::now();
+                    // the step runs in a task of its own, so a panic fails the step instead of the run
+                    let result = match tokio::spawn(async move { run_cached(&client, &pipeline, &next, &step_inputs).await }).await {
+                        Ok(result) => result,
+                        Err(err) => Err(anyhow::anyhow!("Error: step '{}' panicked: {}", name, err)),
+                    };
+                    (name, result, started.elapsed().as_millis() as u64)
+                });
+            }
+
+            let (name, result, duration_ms) = match running.join_next().await {
+                Some(joined) => joined?,
+                None => break,
+            };
+            match result {
+                Ok((value, cached)) => {
+                    values.insert(name.to_owned(), value);
+                    reports.push(StepReport { name, cached, error: None, skipped: false, duration_ms });
+                }
+                Err(err) => {
+                    failed.insert(name.to_owned());
+                    reports.push(StepReport { name, cached: false, error: Some(err.to_string()), skipped: false, duration_ms });
+                }
+            }
+        }
+        Ok(PipelineRun { values, steps: reports })
+    }
+}
+
+// (output, whether it came from the cache)
+async fn run_cached(client: &ClientIdentity, pipeline: &str, step: &PipelineStep, inputs: &BTreeMap<String, PipelineValue>) -> anyhow::Result<(PipelineValue, bool)> {
+    let hash = step.cache_key(client, pipeline, inputs)?;
+    if let Some(value) = PIPELINE_STEP_STORE.get_item_by_hash::<PipelineValue>(hash)? {
+        return Ok((value, true));
+    }
+    let value = step.run(client, inputs).await?;
+    PIPELINE_STEP_STORE.insert_item(hash, value.clone()).ok();
+    Ok((value, false))
+}
+
+// the pipeline inputs from plain texts
+pub fn text_inputs(inputs: &[(&str, &str)]) -> BTreeMap<String, PipelineValue> {
+    inputs.iter().map(|(name, text)| (name.to_string(), PipelineValue::Text(text.to_string()))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(steps: &[(&str, &[&str])]) -> Pipeline {
+        steps.iter().fold(Pipeline::new("test"), |pipeline, (name, inputs)| {
+            pipeline.function(name, inputs, 1, |_| Ok(PipelineValue::Text(String::new())))
+        })
+    }
+
+    fn input_names(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn validate(steps: &[(&str, &[&str])], inputs: &[&str]) -> Result<(), String> {
+        pipeline(steps).validate(&input_names(inputs)).map_err(|x| x.to_string())
+    }
+
+    #[test]
+    fn validate_accepts_a_dag_in_any_order() {
+        assert_eq!(validate(&[("d", &["b", "c"]), ("b", &["a"]), ("c", &["a", "text"]), ("a", &["text"])], &["text"]), Ok(()));
+        assert_eq!(validate(&[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_unknown_inputs_and_duplicate_names() {
+        assert_eq!(validate(&[("a", &["missing"])], &["text"]), Err("Error: unknown input 'missing' in pipeline 'test'".to_string()));
+        assert_eq!(validate(&[("a", &["text"]), ("a", &["text"])], &["text"]), Err("Error: step name 'a' of pipeline 'test' is not unique".to_string()));
+        assert_eq!(validate(&[("text", &[])], &["text"]), Err("Error: step name 'text' of pipeline 'test' is not unique".to_string()));
+    }
+
+    #[test]
+    fn validate_detects_cycles() {
+        assert_eq!(validate(&[("a", &["b"]), ("b", &["a"])], &[]), Err("Error: pipeline 'test' has a cycle between [\"a\", \"b\"]".to_string()));
+        assert_eq!(validate(&[("a", &["a"])], &[]), Err("Error: pipeline 'test' has a cycle between [\"a\"]".to_string()));
+        // a step behind a cycle is blocked too, but it is the cycle that is reported
+        let err = validate(&[("x", &["text"]), ("a", &["c", "x"]), ("b", &["a"]), ("c", &["b"]), ("d", &["c"])], &["text"]).unwrap_err();
+        assert!(err.starts_with("Error: pipeline 'test' has a cycle"), "{}", err);
+    }
+
+    #[test]
+    fn cache_key_depends_on_client_and_input_order() {
+        let client = |client_id: &str, project: Option<&str>| ClientIdentity { client_id: client_id.to_string(), project: project.map(|x| x.to_string()), verified: true };
+        let inputs = text_inputs(&[("a", "first"), ("b", "second")]);
+        let pipeline = Pipeline::new("test").embedding("ab", "text-embedding-ada-002", &["a", "b"]).embedding("ba", "text-embedding-ada-002", &["b", "a"]);
+        let (ab, ba) = (&pipeline.steps()[0], &pipeline.steps()[1]);
+        let key = |step: &PipelineStep, client: &ClientIdentity| step.cache_key(client, "test", &inputs).unwrap();
+
+        let alice = client("alice", None);
+        assert_eq!(key(ab, &alice), key(ab, &client("alice", None)));
+        assert_ne!(key(ab, &alice), key(ab, &client("bob", None)));
+        assert_ne!(key(ab, &alice), key(ab, &client("alice", Some("project"))));
+        let renamed = PipelineStep { name: "ab".to_string(), ..ba.clone() };
+        assert_ne!(key(ab, &alice), key(&renamed, &alice));
+    }
+}
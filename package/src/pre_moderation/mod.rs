@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use linkify::{LinkFinder, LinkKind};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+pub const PRE_MODERATION_RULES_PATH: &str = "./tmp/rust_openai_gpt_tools_pre_moderation.json";
+
+lazy_static!{
+   pub static ref PRE_MODERATOR: PreModerator = load_pre_moderator(PRE_MODERATION_RULES_PATH);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PreModerationAction {
+    Allow,
+    // reported in the verdict, the text is left as it is
+    Flag,
+    Redact,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegexRule {
+    pub name: String,
+    pub pattern: String,
+    pub action: PreModerationAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreModerationRules {
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    #[serde(default)]
+    pub denied_domains: Vec<String>,
+    // domains scammers like to imitate, links that look like them are treated as lookalikes
+    #[serde(default)]
+    pub protected_domains: Vec<String>,
+    pub unknown_link_action: PreModerationAction,
+    pub denied_link_action: PreModerationAction,
+    pub lookalike_link_action: PreModerationAction,
+    #[serde(default)]
+    pub regex_rules: Vec<RegexRule>,
+}
+
+impl Default for PreModerationRules {
+    fn default() -> Self {
+        PreModerationRules {
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            protected_domains: vec![
+                "terra.money".to_string(),
+                "osmosis.zone".to_string(),
+                "cosmos.network".to_string(),
+                "keplr.app".to_string(),
+                "mintscan.io".to_string(),
+            ],
+            unknown_link_action: PreModerationAction::Allow,
+            denied_link_action: PreModerationAction::Reject,
+            lookalike_link_action: PreModerationAction::Flag,
+            regex_rules: vec![RegexRule {
+                name: "seed-phrase".to_string(),
+                pattern: r"(?i)\b(seed|recovery|mnemonic|secret)\s+(phrase|words)\b".to_string(),
+                action: PreModerationAction::Flag,
+            }],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LinkStatus {
+    Allowed,
+    Denied,
+    Lookalike { imitates: String },
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LinkVerdict {
+    pub url: String,
+    pub domain: String,
+    pub start: usize,
+    pub end: usize,
+    pub status: LinkStatus,
+    pub action: PreModerationAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleHit {
+    pub rule: String,
+    pub matched: String,
+    pub start: usize,
+    pub end: usize,
+    pub action: PreModerationAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PreModerationVerdict {
+    pub links: Vec<LinkVerdict>,
+    pub rule_hits: Vec<RuleHit>,
+    // the strictest action of all links and rule hits
+    pub action: PreModerationAction,
+    pub redacted_text: String,
+}
+
+impl PreModerationVerdict {
+    // the strictest action per finding category, named like OpenAI's categories
+    pub fn categories(&self) -> Vec<(String, PreModerationAction)> {
+        let mut categories: BTreeMap<String, PreModerationAction> = BTreeMap::new();
+        for link in self.links.iter() {
+            let category = match link.status {
+                LinkStatus::Denied => "link/denied",
+                LinkStatus::Lookalike { .. } => "link/lookalike",
+                LinkStatus::Unknown => "link/unknown",
+                LinkStatus::Allowed => continue,
+            };
+            let entry = categories.entry(category.to_string()).or_insert(link.action);
+            *entry = (*entry).max(link.action);
+        }
+        for hit in self.rule_hits.iter() {
+            let entry = categories.entry(format!("rule/{}", hit.rule)).or_insert(hit.action);
+            *entry = (*entry).max(hit.action);
+        }
+        categories.into_iter().filter(|(_, action)| *action != PreModerationAction::Allow).collect()
+    }
+}
+
+pub struct PreModerator {
+    rules: PreModerationRules,
+    regex_rules: Vec<(RegexRule, Regex)>,
+}
+
+impl PreModerator {
+    pub fn new(rules: PreModerationRules) -> anyhow::Result<Self> {
+        let mut regex_rules = Vec::new();
+        for rule in rules.regex_rules.iter() {
+            regex_rules.push((rule.clone(), Regex::new(&rule.pattern)?));
+        }
+        Ok(PreModerator { rules, regex_rules })
+    }
+
+    pub fn rules(&self) -> &PreModerationRules {
+        &self.rules
+    }
+
+    pub fn check_domain(&self, domain: &str) -> (LinkStatus, PreModerationAction) {
+        if self.rules.allowed_domains.iter().any(|x| domain_matches(domain, x)) {
+            return (LinkStatus::Allowed, PreModerationAction::Allow);
+        }
+        if self.rules.denied_domains.iter().any(|x| domain_matches(domain, x)) {
+            return (LinkStatus::Denied, self.rules.denied_link_action);
+        }
+        // the real domains are fine, everything resembling them is not
+        if self.rules.protected_domains.iter().any(|x| domain_matches(domain, x)) {
+            return (LinkStatus::Allowed, PreModerationAction::Allow);
+        }
+        if let Some(imitates) = self.rules.protected_domains.iter().find(|x| is_lookalike(domain, x)) {
+            return (LinkStatus::Lookalike { imitates: imitates.to_owned() }, self.rules.lookalike_link_action);
+        }
+        (LinkStatus::Unknown, self.rules.unknown_link_action)
+    }
+
+    pub fn pre_moderate(&self, text: &str) -> PreModerationVerdict {
+        let mut finder = LinkFinder::new();
+        finder.url_must_have_scheme(false);
+        finder.kinds(&[LinkKind::Url]);
+
+        let links = finder.links(text).filter_map(|link| {
+            let domain = extract_domain(link.as_str())?;
+            let (status, action) = self.check_domain(&domain);
+            Some(LinkVerdict {
+                url: link.as_str().to_string(),
+                domain,
+                start: link.start(),
+                end: link.end(),
+                status,
+                action,
+            })
+        }).collect::<Vec<LinkVerdict>>();
+
+        let rule_hits = self.regex_rules.iter().flat_map(|(rule, regex)| {
+            regex.find_iter(text).map(|x| RuleHit {
+                rule: rule.name.to_owned(),
+                matched: x.as_str().to_string(),
+                start: x.start(),
+                end: x.end(),
+                action: rule.action,
+            }).collect::<Vec<RuleHit>>()
+        }).collect::<Vec<RuleHit>>();
+
+        let action = links.iter().map(|x| x.action)
+            .chain(rule_hits.iter().map(|x| x.action))
+            .max()
+            .unwrap_or(PreModerationAction::Allow);
+
+        let mut redactions = links.iter().filter(|x| x.action >= PreModerationAction::Redact).map(|x| (x.start, x.end, "[redacted link]"))
+            .chain(rule_hits.iter().filter(|x| x.action >= PreModerationAction::Redact).map(|x| (x.start, x.end, "[redacted]")))
+            .collect::<Vec<(usize, usize, &str)>>();
+        redactions.sort_by_key(|x| x.0);
+
+        let mut redacted_text = String::new();
+        let mut position = 0;
+        for (start, end, replacement) in redactions {
+            // overlapping matches are already covered
+            if start < position {
+                position = position.max(end);
+                continue;
+            }
+            redacted_text.push_str(&text[position..start]);
+            redacted_text.push_str(replacement);
+            position = end;
+        }
+        redacted_text.push_str(&text[position..]);
+
+        PreModerationVerdict { links, rule_hits, action, redacted_text }
+    }
+}
+
+fn domain_matches(domain: &str, pattern: &str) -> bool {
+    let pattern = pattern.trim_start_matches("*.").to_lowercase();
+    domain == pattern || domain.ends_with(&format!(".{}", pattern))
+}
+
+pub fn extract_domain(url: &str) -> Option<String> {
+    let without_scheme = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    let host = without_scheme.split(['/', '?', '#']).next()?;
+    let host = host.rsplit('@').next()?;
+    let host = host.split(':').next()?.trim_end_matches('.').to_lowercase();
+    if host.is_empty() || !host.contains('.') {
+        return None;
+    }
+    Some(host)
+}
+
+// second-level labels that country code domains register under, as in "co.uk" or "com.au"
+const SECOND_LEVEL_SUFFIXES: [&str; 8] = ["co", "com", "net", "org", "gov", "edu", "ac", "or"];
+
+// "v2terra.de" -> "v2terra", "terra.co.uk" -> "terra", the label that is registered under the public suffix
+fn registered_label(domain: &str) -> &str {
+    let labels = domain.split('.').collect::<Vec<&str>>();
+    let n = labels.len();
+    if n >= 3 && labels[n - 1].len() == 2 && SECOND_LEVEL_SUFFIXES.contains(&labels[n - 2]) {
+        labels[n - 3]
+    } else if n >= 2 {
+        labels[n - 2]
+    } else {
+        domain
+    }
+}
+
+// RFC 3492, decodes the part of an "xn--" label after the prefix, None for invalid input
+fn decode_punycode(input: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const T_MIN: u32 = 1;
+    const T_MAX: u32 = 26;
+    const SKEW: u32 = 38;
+    const DAMP: u32 = 700;
+
+    fn adapt(delta: u32, num_points: u32, first_time: bool) -> u32 {
+        let mut delta = if first_time { delta / DAMP } else { delta / 2 };
+        delta += delta / num_points;
+        let mut k = 0;
+        while delta > ((BASE - T_MIN) * T_MAX) / 2 {
+            delta /= BASE - T_MIN;
+            k += BASE;
+        }
+        k + (BASE - T_MIN + 1) * delta / (delta + SKEW)
+    }
+
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output = basic.chars().collect::<Vec<char>>();
+    let mut digits = extended.chars();
+    let mut n: u32 = 128;
+    let mut i: u32 = 0;
+    let mut bias: u32 = 72;
+    while digits.as_str() != "" {
+        let old_i = i;
+        let mut w: u32 = 1;
+        let mut k = BASE;
+        loop {
+            let digit = match digits.next()? {
+                c @ 'a'..='z' => c as u32 - 'a' as u32,
+                c @ 'A'..='Z' => c as u32 - 'A' as u32,
+                c @ '0'..='9' => c as u32 - '0' as u32 + 26,
+                _ => return None,
+            };
+            i = i.checked_add(digit.checked_mul(w)?)?;
+            let t = if k <= bias { T_MIN } else if k >= bias + T_MAX { T_MAX } else { k - bias };
+            if digit < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let length = output.len() as u32 + 1;
+        bias = adapt(i - old_i, length, old_i == 0);
+        n = n.checked_add(i / length)?;
+        i %= length;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+// "xn--" labels are compared by their unicode form, undecodable labels are compared as they are
+fn decode_label(label: &str) -> String {
+    match label.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("xn--") => decode_punycode(&label[4..]).unwrap_or_else(|| label.to_string()),
+        _ => label.to_string(),
+    }
+}
+
+// cyrillic, greek and accented letters that look like latin ones
+fn fold_confusable(c: char) -> char {
+    match c {
+        'а' | 'α' | 'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => 'a',
+        'в' | 'β' | 'ь' => 'b',
+        'с' | 'ç' | 'ć' | 'č' | 'ϲ' => 'c',
+        'ԁ' | 'ď' | 'đ' => 'd',
+        'е' | 'ё' | 'ε' | 'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => 'e',
+        'ɡ' | 'ğ' | 'ģ' => 'g',
+        'һ' | 'н' | 'η' => 'h',
+        'і' | 'ї' | 'ι' | 'ì' | 'í' | 'î' | 'ï' | 'ı' | 'ī' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'ӏ' | 'ł' | 'ľ' => 'l',
+        'м' => 'm',
+        'п' | 'ñ' | 'ń' | 'ň' => 'n',
+        'о' | 'ο' | 'σ' | 'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'г' | 'ř' => 'r',
+        'ѕ' | 'ś' | 'š' | 'ş' => 's',
+        'т' | 'τ' | 'ť' | 'ţ' => 't',
+        'υ' | 'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' => 'u',
+        'ν' => 'v',
+        'ѡ' | 'ω' => 'w',
+        'х' | 'χ' => 'x',
+        'у' | 'ү' | 'ý' | 'ÿ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        c => c,
+    }
+}
+
+fn normalize_homoglyphs(label: &str) -> String {
+    label.to_lowercase()
+        .chars()
+        .map(fold_confusable)
+        .collect::<String>()
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' | 'i' => 'l',
+            '3' => 'e',
+            '4' => 'a',
+            '5' => 's',
+            '7' => 't',
+            '8' => 'b',
+            '-' | '_' => ' ',
+            c => c,
+        })
+        .filter(|c| *c != ' ')
+        .collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<char>>();
+    let b = b.chars().collect::<Vec<char>>();
+    let mut previous = (0..=b.len()).collect::<Vec<usize>>();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// homoglyphs, including those of decoded punycode labels, typos and the protected name as a word of a longer label,
+// e.g. "terra-airdrop", one typo is tolerated per five characters so that short names do not match every similar word
+pub fn is_lookalike(domain: &str, protected_domain: &str) -> bool {
+    let protected_domain = protected_domain.to_lowercase();
+    if domain_matches(domain, &protected_domain) {
+        return false;
+    }
+    let domain = domain.split('.').map(decode_label).collect::<Vec<String>>().join(".");
+    let domain = domain.as_str();
+    let label = registered_label(domain);
+    let protected_label = registered_label(&protected_domain);
+    let normalized = normalize_homoglyphs(label);
+    let protected_normalized = normalize_homoglyphs(protected_label);
+    if protected_normalized.len() < 4 {
+        return normalized == protected_normalized;
+    }
+    let max_distance = protected_normalized.chars().count() / 5;
+    normalized == protected_normalized
+        || edit_distance(&normalized, &protected_normalized) <= max_distance
+        || label.split(['-', '_']).any(|x| normalize_homoglyphs(x) == protected_normalized)
+        || domain.split('.').any(|x| normalize_homoglyphs(x) == protected_normalized)
+}
+
+pub fn load_pre_moderator(path: &str) -> PreModerator {
+    let rules = match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<PreModerationRules>(&json) {
+            Ok(rules) => rules,
+            Err(err) => {
+                println!("Error: invalid pre-moderation rules at '{}': {}, using defaults", path, err);
+                PreModerationRules::default()
+            }
+        },
+        Err(_) => PreModerationRules::default(),
+    };
+    match PreModerator::new(rules) {
+        Ok(pre_moderator) => pre_moderator,
+        Err(err) => {
+            println!("Error: invalid regex rule in '{}': {}, using defaults", path, err);
+            PreModerator::new(PreModerationRules::default()).unwrap()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_label_skips_multi_part_suffixes() {
+        assert_eq!(registered_label("v2terra.de"), "v2terra");
+        assert_eq!(registered_label("app.terra.money"), "terra");
+        assert_eq!(registered_label("terra.co.uk"), "terra");
+        assert_eq!(registered_label("shop.terra.com.au"), "terra");
+        assert_eq!(registered_label("localhost"), "localhost");
+    }
+
+    #[test]
+    fn is_lookalike_flags_imitations() {
+        for domain in ["terra-airdrop.com", "airdrop_terra.io", "terr4.money", "terrra.com", "terra.co.uk", "terra.scam.io"] {
+            assert!(is_lookalike(domain, "terra.money"), "{}", domain);
+        }
+        assert!(is_lookalike("osmosls.zone", "osmosis.zone"));
+        assert!(is_lookalike("0smosis.zone", "osmosis.zone"));
+    }
+
+    #[test]
+    fn is_lookalike_ignores_similar_words() {
+        for domain in ["terra.money", "app.terra.money", "mediterranean.com", "sierra.com", "terrace.com", "terrarium.co.uk"] {
+            assert!(!is_lookalike(domain, "terra.money"), "{}", domain);
+        }
+        assert!(!is_lookalike("osmosis-labs.com", "cosmos.network"));
+    }
+
+    #[test]
+    fn decodes_punycode() {
+        assert_eq!(decode_punycode("mnchen-3ya").as_deref(), Some("münchen"));
+        assert_eq!(decode_punycode("bcher-kva").as_deref(), Some("bücher"));
+        assert_eq!(decode_punycode("fiq228c").as_deref(), Some("中文"));
+        assert_eq!(decode_punycode("terr-83d").as_deref(), Some("terrа"));
+        assert_eq!(decode_punycode("abc-!"), None);
+        assert_eq!(decode_punycode("99999999999"), None);
+    }
+
+    #[test]
+    fn is_lookalike_compares_decoded_punycode() {
+        // "terrа" with a cyrillic "а", "tеrrа" with a cyrillic "е" and "а", "térra"
+        for domain in ["xn--terr-83d.money", "xn--trr-8cdw.com", "XN--TRRA-BPA.io", "app.xn--terr-83d.money"] {
+            assert!(is_lookalike(domain, "terra.money"), "{}", domain);
+        }
+        // "osmоsis" with a cyrillic "о"
+        assert!(is_lookalike("xn--osmsis-yqf.zone", "osmosis.zone"));
+        for domain in ["xn--mnchen-3ya.de", "xn--bcher-kva.com", "xn--fiq228c.cn", "xn--invalid-!.com"] {
+            assert!(!is_lookalike(domain, "terra.money"), "{}", domain);
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTChatCompletionRequest, OpenAIGPTRequest, OpenAIGPTResult, OpenAIGPTTextCompletionRequest};
+
+use crate::budget::ClientIdentity;
+use crate::service::process_request;
+
+pub const PROMPT_TEMPLATE_DIR: &str = "./tmp/prompts";
+
+// what the endpoints stop at unless a template derives its own
+pub const DEFAULT_STOP_TOKENS: [&str; 3] = ["<result", "<result>", "</result>"];
+
+// OpenAI rejects requests with more stop sequences
+pub const MAX_STOP_SEQUENCES: usize = 4;
+
+lazy_static!{
+   static ref TRAILING_OPEN_TAG: Regex = Regex::new(r"<([A-Za-z_][\w-]*)(?:\s[^<>]*)?>\s*$").unwrap();
+}
+
+pub fn default_stop_tokens() -> Vec<String> {
+    DEFAULT_STOP_TOKENS.iter().map(|x| x.to_string()).collect()
+}
+
+pub fn validate_stop_tokens(stop: &[String]) -> anyhow::Result<()> {
+    if stop.len() > MAX_STOP_SEQUENCES {
+        return Err(anyhow::anyhow!("Error: {} stop sequences given, at most {} are allowed", stop.len(), MAX_STOP_SEQUENCES));
+    }
+    Ok(())
+}
+
+// user content can not open or close tags, the model still reads it fine
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// for values inside a quoted attribute, e.g. <source id='...'>
+pub fn escape_attribute(text: &str) -> String {
+    escape(text).replace('\'', "&apos;").replace('"', "&quot;")
+}
+
+// a prompt that ends with an opening tag is answered with the tag's content, so the answer stops where the tag would close or reopen
+pub fn derive_stop_tokens(prompt: &str) -> Vec<String> {
+    match TRAILING_OPEN_TAG.captures(prompt) {
+        Some(captures) => vec![format!("<{}", &captures[1]), format!("</{}>", &captures[1])],
+        None => Vec::new(),
+    }
+}
+
+// "{{name}}" is replaced by the escaped value, "{{{name}}}" by the raw value for trusted content
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub version: u32,
+    #[serde(default)]
+    pub system: Option<String>,
+    pub template: String,
+    // overrides the derived stop tokens
+    #[serde(default)]
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part<'a> {
+    Text(&'a str),
+    Variable { name: &'a str, raw: bool },
+}
+
+fn parse(template: &str) -> anyhow::Result<Vec<Part<'_>>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        parts.push(Part::Text(&rest[..start]));
+        let raw = rest[start..].starts_with("{{{");
+        let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+        let after_open = &rest[start + open.len()..];
+        let end = after_open.find(close).ok_or_else(|| anyhow::anyhow!("Error: unclosed variable in template at '{}'", &rest[start..]))?;
+        let name = after_open[..end].trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.') {
+            return Err(anyhow::anyhow!("Error: invalid variable name '{}' in template", name));
+        }
+        parts.push(Part::Variable { name, raw });
+        rest = &after_open[end + close.len()..];
+    }
+    parts.push(Part::Text(rest));
+    Ok(parts)
+}
+
+fn render_text(template: &str, variables: &BTreeMap<&str, &str>) -> anyhow::Result<String> {
+    let mut rendered = String::new();
+    for part in parse(template)? {
+        match part {
+            Part::Text(text) => rendered.push_str(text),
+            Part::Variable { name, raw } => {
+                let value = variables.get(name).ok_or_else(|| anyhow::anyhow!("Error: missing template variable '{}'", name))?;
+                rendered.push_str(&if raw { value.to_string() } else { escape(value) });
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+impl PromptTemplate {
+    pub fn new(name: &str, version: u32, system: Option<&str>, template: &str) -> Self {
+        PromptTemplate {
+            name: name.to_string(),
+            version,
+            system: system.map(|x| x.to_string()),
+            template: template.to_string(),
+            stop: None,
+        }
+    }
+
+    // the variables of the system and prompt template, in order of appearance
+    pub fn variables(&self) -> anyhow::Result<Vec<String>> {
+        let mut variables: Vec<String> = Vec::new();
+        for template in self.system.iter().chain(std::iter::once(&self.template)) {
+            for part in parse(template)? {
+                if let Part::Variable { name, .. } = part {
+                    if !variables.iter().any(|x| x == name) {
+                        variables.push(name.to_string());
+                    }
+                }
+            }
+        }
+        Ok(variables)
+    }
+
+    pub fn render(&self, variables: &BTreeMap<&str, &str>) -> anyhow::Result<RenderedPrompt> {
+        if let Some(stop) = &self.stop {
+            validate_stop_tokens(stop)?;
+        }
+        let prompt = render_text(&self.template, variables)?;
+        let system = match &self.system {
+            Some(system) => Some(render_text(system, variables)?),
+            None => None,
+        };
+        let stop = self.stop.clone().unwrap_or_else(|| derive_stop_tokens(&prompt));
+        Ok(RenderedPrompt { system, prompt, stop })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RenderedPrompt {
+    pub system: Option<String>,
+    pub prompt: String,
+    pub stop: Vec<String>,
+}
+
+// both completions go through the service, so they are moderated, cached and paid from the client's budget
+impl RenderedPrompt {
+    pub async fn text_completion(&self, client: &ClientIdentity, model_name: &str, completion_token_limit: u16) -> anyhow::Result<String> {
+        let prompt = match &self.system {
+            Some(system) => format!("{}\n\n{}", system, self.prompt),
+            None => self.prompt.to_owned(),
+        };
+        let request = OpenAIGPTRequest::TextCompletionRequest(OpenAIGPTTextCompletionRequest {
+            model_name: Some(model_name.to_string()),
+            prompt,
+            completion_token_limit,
+            moderation: None,
+            injection: None,
+            stop: Some(self.stop.to_owned()),
+        });
+        match process_request(client, request).await? {
+            OpenAIGPTResult::TextCompletionResult(result) => Ok(result.result),
+            OpenAIGPTResult::ModerationRejectionResult(rejection) => Err(anyhow::anyhow!("Error: completion rejected by moderation: {:?}", rejection.categories)),
+            result => Err(anyhow::anyhow!("Error: unexpected result {:?}", result)),
+        }
+    }
+
+    pub async fn chat_completion(&self, client: &ClientIdentity, model_name: &str, completion_token_limit: u16) -> anyhow::Result<String> {
+        let request = OpenAIGPTRequest::ChatCompletionRequest(OpenAIGPTChatCompletionRequest {
+            model_name: model_name.to_string(),
+            system: self.system.to_owned().unwrap_or_default(),
+            prompt: self.prompt.to_owned(),
+            completion_token_limit,
+            routing: None,
+            moderation: None,
+            injection: None,
+            stop: Some(self.stop.to_owned()),
+        });
+        match process_request(client, request).await? {
+            OpenAIGPTResult::ChatCompletionResult(result) => Ok(result.result),
+            OpenAIGPTResult::ModerationRejectionResult(rejection) => Err(anyhow::anyhow!("Error: completion rejected by moderation: {:?}", rejection.categories)),
+            result => Err(anyhow::anyhow!("Error: unexpected result {:?}", result)),
+        }
+    }
+}
+
+// every version of every template, by name
+#[derive(Debug, Clone, Default)]
+pub struct PromptTemplates {
+    templates: BTreeMap<String, BTreeMap<u32, PromptTemplate>>,
+}
+
+impl PromptTemplates {
+    pub fn insert(&mut self, template: PromptTemplate) -> anyhow::Result<()> {
+        // fails early on a broken template instead of at render time
+        template.variables()?;
+        if let Some(stop) = &template.stop {
+            validate_stop_tokens(stop)?;
+        }
+        self.templates.entry(template.name.to_owned()).or_default().insert(template.version, template);
+        Ok(())
+    }
+
+    // the latest version
+    pub fn get(&self, name: &str) -> Option<&PromptTemplate> {
+        self.templates.get(name).and_then(|x| x.values().next_back())
+    }
+
+    pub fn get_version(&self, name: &str, version: u32) -> Option<&PromptTemplate> {
+        self.templates.get(name).and_then(|x| x.get(&version))
+    }
+
+    pub fn versions(&self, name: &str) -> Vec<u32> {
+        self.templates.get(name).map(|x| x.keys().copied().collect()).unwrap_or_default()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.templates.keys().map(|x| x.as_str()).collect()
+    }
+
+    pub fn render(&self, name: &str, variables: &BTreeMap<&str, &str>) -> anyhow::Result<RenderedPrompt> {
+        self.get(name).ok_or_else(|| anyhow::anyhow!("Error: unknown prompt template '{}'", name))?.render(variables)
+    }
+}
+
+pub fn default_prompt_templates() -> Vec<PromptTemplate> {
+    vec![
+        PromptTemplate::new("summarize", 1, None, "<instruction>Summarize the following</instruction><source>{{source}}</source>\n\n<result>"),
+        PromptTemplate::new("proposal_perception", 1, None, "<proposal>{{proposal}}</proposal><result description='Describe how this proposal may be perceived by the community, including potential reactions of both acceptance and rejection.'>"),
+        PromptTemplate::new("proposal_nutshell", 1, None, "<proposal>{{proposal}}</proposal><result description='Describe the proposal in a nutshell, including the most important points to consider when deciding whether to support it or not.'>"),
+    ]
+}
+
+// every *.json file in the directory holds one template, they are added to the default templates
+pub fn load_prompt_templates(path: &str) -> PromptTemplates {
+    let mut templates = PromptTemplates::default();
+    for template in default_prompt_templates() {
+        templates.insert(template).unwrap();
+    }
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return templates,
+    };
+    let mut files = entries.filter_map(|x| x.ok()).map(|x| x.path())
+        .filter(|x| x.extension().map(|x| x == "json").unwrap_or(false))
+        .collect::<Vec<std::path::PathBuf>>();
+    files.sort();
+    for file in files {
+        let loaded = std::fs::read_to_string(&file).map_err(anyhow::Error::from)
+            .and_then(|json| Ok(serde_json::from_str::<PromptTemplate>(&json)?))
+            .and_then(|template| templates.insert(template));
+        if let Err(err) = loaded {
+            println!("Error: invalid prompt template at '{}': {}, skipping", file.display(), err);
+        }
+    }
+    templates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_templates() {
+        assert_eq!(parse("a {{x}} b {{{ y }}}").unwrap(), vec![
+            Part::Text("a "),
+            Part::Variable { name: "x", raw: false },
+            Part::Text(" b "),
+            Part::Variable { name: "y", raw: true },
+            Part::Text(""),
+        ]);
+        assert_eq!(parse("no variables").unwrap(), vec![Part::Text("no variables")]);
+        assert!(parse("{{unclosed").is_err());
+        assert!(parse("{{}}").is_err());
+        assert!(parse("{{not a name}}").is_err());
+    }
+
+    #[test]
+    fn escapes_variables_unless_raw() {
+        let template = PromptTemplate::new("t", 1, Some("{{{rules}}}"), "<source>{{source}}</source>{{{trusted}}}");
+        assert_eq!(template.variables().unwrap(), vec!["rules", "source", "trusted"]);
+        let variables = BTreeMap::from([("rules", "<b>"), ("source", "</source><x & y>"), ("trusted", "<i>")]);
+        let rendered = template.render(&variables).unwrap();
+        assert_eq!(rendered.system.as_deref(), Some("<b>"));
+        assert_eq!(rendered.prompt, "<source>&lt;/source&gt;&lt;x &amp; y&gt;</source><i>");
+        assert!(template.render(&BTreeMap::from([("rules", "")])).is_err());
+        assert_eq!(escape_attribute("a'b\"<c>"), "a&apos;b&quot;&lt;c&gt;");
+    }
+
+    #[test]
+    fn derives_stop_tokens_from_the_trailing_tag() {
+        assert_eq!(derive_stop_tokens("<source>x</source>\n\n<result>"), vec!["<result", "</result>"]);
+        assert_eq!(derive_stop_tokens("<proposal>x</proposal><result description='Describe the proposal.'>"), vec!["<result", "</result>"]);
+        assert_eq!(derive_stop_tokens("<answer-text>  \n"), vec!["<answer-text", "</answer-text>"]);
+        assert!(derive_stop_tokens("no tag at the end").is_empty());
+        assert!(derive_stop_tokens("<result></result>").is_empty());
+
+        let mut template = PromptTemplate::new("t", 1, None, "{{x}}<result>");
+        assert_eq!(template.render(&BTreeMap::from([("x", "")])).unwrap().stop, vec!["<result", "</result>"]);
+        template.stop = Some(vec!["END".to_string()]);
+        assert_eq!(template.render(&BTreeMap::from([("x", "")])).unwrap().stop, vec!["END"]);
+        template.stop = Some(vec!["1", "2", "3", "4", "5"].into_iter().map(|x| x.to_string()).collect());
+        assert!(template.render(&BTreeMap::from([("x", "")])).is_err());
+    }
+
+    #[test]
+    fn loads_versioned_templates() {
+        let dir = std::env::temp_dir().join(format!("rust_openai_gpt_tools_prompts_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |file: &str, template: &PromptTemplate| std::fs::write(dir.join(file), serde_json::to_string(template).unwrap()).unwrap();
+        write("greet_1.json", &PromptTemplate::new("greet", 1, None, "Hello {{name}}"));
+        write("greet_2.json", &PromptTemplate::new("greet", 2, None, "Hi {{name}}"));
+        write("summarize_2.json", &PromptTemplate::new("summarize", 2, None, "<source>{{source}}</source><result>"));
+        write("broken.json", &PromptTemplate::new("broken", 1, None, "{{unclosed"));
+        std::fs::write(dir.join("invalid.json"), "{").unwrap();
+        std::fs::write(dir.join("ignored.txt"), "not a template").unwrap();
+
+        let templates = load_prompt_templates(dir.to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(templates.versions("greet"), vec![1, 2]);
+        assert_eq!(templates.get("greet").unwrap().version, 2);
+        assert_eq!(templates.get_version("greet", 1).unwrap().template, "Hello {{name}}");
+        assert_eq!(templates.render("greet", &BTreeMap::from([("name", "<you>")])).unwrap().prompt, "Hi &lt;you&gt;");
+        // a file adds a version next to the default template
+        assert_eq!(templates.versions("summarize"), vec![1, 2]);
+        assert!(templates.get("broken").is_none());
+        assert!(templates.render("unknown", &BTreeMap::new()).is_err());
+        assert_eq!(templates.names(), vec!["greet", "proposal_nutshell", "proposal_perception", "summarize"]);
+    }
+}
@@ -0,0 +1,216 @@
+use std::future::Future;
+
+use lazy_static::lazy_static;
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTErrorClass, OpenAIGPTRouting, OpenAIGPTRoutingPolicy};
+use serde::{Deserialize, Serialize};
+
+use crate::api::ApiError;
+use crate::models::{ModelEndpoint, ModelInfo, ModelUnavailable, MODEL_REGISTRY};
+use crate::tokenizer::ContextWindowExceeded;
+
+pub const ROUTING_CONFIG_PATH: &str = "./tmp/rust_openai_gpt_tools_routing.json";
+
+lazy_static!{
+   pub static ref ROUTING_CONFIG: RoutingConfig = load_routing_config(ROUTING_CONFIG_PATH);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoutingConfig {
+    // used when a request names a policy but no models
+    pub default_candidates: Vec<String>,
+    // below this share of remaining budget the budget-aware policy goes cheapest first
+    pub budget_aware_threshold: f64,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        RoutingConfig {
+            default_candidates: vec!["gpt-4".to_string(), "gpt-3.5-turbo".to_string()],
+            budget_aware_threshold: 0.5,
+        }
+    }
+}
+
+pub fn load_routing_config(path: &str) -> RoutingConfig {
+    match std::fs::read_to_string(path) {
+        Ok(json) => match serde_json::from_str::<RoutingConfig>(&json) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Error: invalid routing config at '{}': {}, using defaults", path, err);
+                RoutingConfig::default()
+            }
+        },
+        Err(_) => RoutingConfig::default(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BudgetExceeded {
+    pub model_name: String,
+    pub estimated_costs: f64,
+    pub remaining_budget: f64,
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error: Rate Exceeded! ('{}' may cost ${:.4}, only ${:.4} left)", self.model_name, self.estimated_costs, self.remaining_budget)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+pub fn classify_error(err: &anyhow::Error) -> OpenAIGPTErrorClass {
+    if err.downcast_ref::<BudgetExceeded>().is_some() {
+        return OpenAIGPTErrorClass::BudgetExceeded;
+    }
+    if err.downcast_ref::<ContextWindowExceeded>().is_some() {
+        return OpenAIGPTErrorClass::ContextLengthExceeded;
+    }
+    if err.downcast_ref::<ModelUnavailable>().is_some() {
+        return OpenAIGPTErrorClass::ModelUnavailable;
+    }
+    if let Some(api_error) = err.downcast_ref::<ApiError>() {
+        return classify_api_error(api_error);
+    }
+    if let Some(reqwest_error) = err.downcast_ref::<reqwest::Error>() {
+        if reqwest_error.is_timeout() {
+            return OpenAIGPTErrorClass::Timeout;
+        }
+    }
+    OpenAIGPTErrorClass::Other
+}
+
+fn classify_api_error(api_error: &ApiError) -> OpenAIGPTErrorClass {
+    match (api_error.code.as_deref(), api_error.error_type.as_deref(), api_error.status) {
+        (Some("context_length_exceeded"), _, _) => OpenAIGPTErrorClass::ContextLengthExceeded,
+        (Some("model_not_found"), _, _) | (_, _, 404) => OpenAIGPTErrorClass::ModelUnavailable,
+        // an exhausted quota is not solved by waiting or by another model
+        (Some("insufficient_quota"), _, _) | (_, Some("insufficient_quota"), _) => OpenAIGPTErrorClass::Other,
+        (_, _, 429) => OpenAIGPTErrorClass::RateLimited,
+        (_, _, 503) => OpenAIGPTErrorClass::Overloaded,
+        (_, Some("server_error"), _) | (_, _, 500..=599) => OpenAIGPTErrorClass::ServerError,
+        _ => OpenAIGPTErrorClass::Other,
+    }
+}
+
+fn order_models(models: &mut [String], policy: OpenAIGPTRoutingPolicy, remaining_budget_fraction: f64) {
+    let policy = match policy {
+        OpenAIGPTRoutingPolicy::BudgetAware if remaining_budget_fraction < ROUTING_CONFIG.budget_aware_threshold => OpenAIGPTRoutingPolicy::CheapestFirst,
+        OpenAIGPTRoutingPolicy::BudgetAware => OpenAIGPTRoutingPolicy::QualityFirst,
+        policy => policy,
+    };
+    // unknown models keep their place at the end, they fail validation anyway
+    let price = |model_name: &String| MODEL_REGISTRY.get(model_name)
+        .map(|x| x.pricing.prompt_per_1k_token + x.pricing.completion_per_1k_token)
+        .unwrap_or(f64::MAX);
+    let quality = |model_name: &String| MODEL_REGISTRY.get(model_name).map(|x| x.quality as i32).unwrap_or(-1);
+    match policy {
+        OpenAIGPTRoutingPolicy::CheapestFirst => models.sort_by(|a, b| price(a).total_cmp(&price(b))),
+        _ => models.sort_by(|a, b| quality(b).cmp(&quality(a)).then(price(a).total_cmp(&price(b)))),
+    }
+}
+
+pub fn candidate_models(model_name: &str, routing: Option<&OpenAIGPTRouting>, remaining_budget_fraction: f64) -> Vec<String> {
+    let routing = match routing {
+        Some(routing) => routing,
+        None => return vec![model_name.to_string()],
+    };
+    let mut models = if !routing.models.is_empty() {
+        routing.models.clone()
+    } else if routing.policy.is_some() {
+        ROUTING_CONFIG.default_candidates.clone()
+    } else {
+        vec![model_name.to_string()]
+    };
+    if let Some(policy) = routing.policy {
+        order_models(&mut models, policy, remaining_budget_fraction);
+    }
+    models
+}
+
+// tries the candidates in order until one succeeds or fails with an error that does not allow a fallback
+pub async fn route<F, T, R>(candidates: Vec<String>, endpoint: ModelEndpoint, fallback_on: &[OpenAIGPTErrorClass], attempt: F) -> anyhow::Result<(ModelInfo, R)>
+    where
+        F: Fn(ModelInfo) -> T,
+        T: Future<Output = anyhow::Result<R>>
+{
+    let mut last_error = None;
+    for (i, model_name) in candidates.iter().enumerate() {
+        let result = match MODEL_REGISTRY.validate(model_name, endpoint) {
+            Ok(model) => attempt(model.clone()).await.map(|x| (model, x)),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(o) => return Ok(o),
+            Err(err) => {
+                let error_class = classify_error(&err);
+                if i + 1 < candidates.len() && fallback_on.contains(&error_class) {
+                    println!("Routing: '{}' failed with {:?} ({}), falling back to '{}'", model_name, error_class, err, candidates[i + 1]);
+                    last_error = Some(err);
+                } else {
+                    return Err(err);
+                }
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Error: no model to route the request to")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: u16, error_type: Option<&str>, code: Option<&str>) -> anyhow::Error {
+        ApiError {
+            status,
+            message: "error".to_string(),
+            error_type: error_type.map(|x| x.to_string()),
+            code: code.map(|x| x.to_string()),
+        }.into()
+    }
+
+    #[test]
+    fn classifies_api_errors() {
+        assert_eq!(classify_error(&api_error(400, Some("invalid_request_error"), Some("context_length_exceeded"))), OpenAIGPTErrorClass::ContextLengthExceeded);
+        assert_eq!(classify_error(&api_error(400, Some("invalid_request_error"), Some("model_not_found"))), OpenAIGPTErrorClass::ModelUnavailable);
+        assert_eq!(classify_error(&api_error(404, None, None)), OpenAIGPTErrorClass::ModelUnavailable);
+        assert_eq!(classify_error(&api_error(429, Some("requests"), Some("rate_limit_exceeded"))), OpenAIGPTErrorClass::RateLimited);
+        assert_eq!(classify_error(&api_error(429, Some("insufficient_quota"), Some("insufficient_quota"))), OpenAIGPTErrorClass::Other);
+        assert_eq!(classify_error(&api_error(503, None, None)), OpenAIGPTErrorClass::Overloaded);
+        assert_eq!(classify_error(&api_error(500, Some("server_error"), None)), OpenAIGPTErrorClass::ServerError);
+        assert_eq!(classify_error(&api_error(200, Some("server_error"), None)), OpenAIGPTErrorClass::ServerError);
+        assert_eq!(classify_error(&api_error(400, Some("invalid_request_error"), None)), OpenAIGPTErrorClass::Other);
+    }
+
+    #[test]
+    fn classifies_local_errors() {
+        let budget_exceeded = BudgetExceeded { model_name: "gpt-4".to_string(), estimated_costs: 1.0, remaining_budget: 0.5 };
+        assert_eq!(classify_error(&budget_exceeded.into()), OpenAIGPTErrorClass::BudgetExceeded);
+        assert_eq!(classify_error(&MODEL_REGISTRY.validate("unknown-model", ModelEndpoint::Chat).unwrap_err()), OpenAIGPTErrorClass::ModelUnavailable);
+        assert_eq!(classify_error(&MODEL_REGISTRY.validate("gpt-4", ModelEndpoint::Embedding).unwrap_err()), OpenAIGPTErrorClass::ModelUnavailable);
+        assert_eq!(classify_error(&crate::tokenizer::clamp_completion_tokens("gpt-4", 10_000, 100).unwrap_err()), OpenAIGPTErrorClass::ContextLengthExceeded);
+        // the wording of a message alone does not classify an error
+        assert_eq!(classify_error(&anyhow::anyhow!("Error: unknown model 'gpt-4'")), OpenAIGPTErrorClass::Other);
+    }
+
+    #[test]
+    fn orders_models_by_policy() {
+        let candidates = vec!["gpt-3.5-turbo".to_string(), "unknown-model".to_string(), "gpt-4o-mini".to_string(), "gpt-4".to_string()];
+
+        let mut models = candidates.clone();
+        order_models(&mut models, OpenAIGPTRoutingPolicy::CheapestFirst, 1.0);
+        assert_eq!(models, vec!["gpt-4o-mini", "gpt-3.5-turbo", "gpt-4", "unknown-model"]);
+
+        let mut models = candidates.clone();
+        order_models(&mut models, OpenAIGPTRoutingPolicy::QualityFirst, 1.0);
+        assert_eq!(models, vec!["gpt-4", "gpt-4o-mini", "gpt-3.5-turbo", "unknown-model"]);
+
+        let mut models = candidates.clone();
+        order_models(&mut models, OpenAIGPTRoutingPolicy::BudgetAware, 0.9);
+        assert_eq!(models, vec!["gpt-4", "gpt-4o-mini", "gpt-3.5-turbo", "unknown-model"]);
+
+        let mut models = candidates;
+        order_models(&mut models, OpenAIGPTRoutingPolicy::BudgetAware, 0.1);
+        assert_eq!(models, vec!["gpt-4o-mini", "gpt-3.5-turbo", "gpt-4", "unknown-model"]);
+    }
+}
@@ -1,240 +1,479 @@
 use std::sync::{Arc, Mutex};
-use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTChatCompletionResult, OpenAIGPTEmbeddingResult, OpenAIGPTRequest, OpenAIGPTResult, OpenAIGPTTextCompletionResult};
-use rust_openai_gpt_tools_socket_ipc::ipc::socket::{spawn_socket_service};
-use crate::text_completion::{completion_endpoint, TextCompletion};
-use crate::chat_completion::{chat_completion_endpoint, ChatCompletion};
-use crate::embedding::{embedding_endpoint};
-use crate::moderation::{moderation_endpoint};
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTChatCompletionResult, OpenAIGPTChatSessionRequest, OpenAIGPTChatSessionResult, OpenAIGPTEmbeddingRequest, OpenAIGPTEmbeddingResult, OpenAIGPTFraudClassificationRequest, OpenAIGPTFraudClassificationResult, OpenAIGPTFraudLabel, OpenAIGPTInjectionMode, OpenAIGPTInjectionReport, OpenAIGPTModerationCategory, OpenAIGPTModerationRejection, OpenAIGPTModerationMode, OpenAIGPTModerationSide, OpenAIGPTRequest, OpenAIGPTResult, OpenAIGPTTextCompletionResult, OpenAIGPTModelThrottleStatus, OpenAIGPTThrottleStatusResult};
+use rust_openai_gpt_tools_socket_ipc::ipc::socket::{spawn_socket_service, PeerCredentials};
+use crate::text_completion::{completion_endpoint_with_stop, TextCompletion};
+use crate::chat_completion::{chat_completion_endpoint_with_stop, ChatCompletion};
+use crate::embedding::{cached_embedding_endpoint, DEFAULT_EMBEDDING_MODEL};
+use crate::moderation::{redact, ModerationRejection, ModerationSide, MODERATION_BACKEND, MODERATION_POLICY};
+use crate::moderation::backend::ModerationBackend;
+use crate::injection::{GuardedPrompt, InjectionReport, INJECTION_GUARD};
+use crate::fraud::{FraudLabel, FRAUD_DETECTOR};
+use crate::conversation::{lock_session, Conversation, CONVERSATION_CONFIG};
+use crate::throttle::throttle_status;
+use crate::prompt::{default_stop_tokens, validate_stop_tokens};
 
 use tokio::task::JoinHandle;
 
 
 use lazy_static::lazy_static;
-use crate::cache::HashValueStore;
-
-use std::time::{Instant, Duration};
+use crate::cache::{digest, HashValueStore, SLED_DB, load_sled_db};
+use crate::models::{MODEL_REGISTRY, ModelEndpoint, ModelInfo};
+use crate::embedding::Usage;
+use crate::api::PartiallyBilled;
+use crate::routing::{candidate_models, route, BudgetExceeded};
+use crate::tokenizer::{count_chat_tokens, count_tokens};
+use crate::budget::{BudgetAccounts, ClientIdentity, load_budget_config, BUDGET_CONFIG_PATH};
 
 lazy_static!{
-   static ref OPENAI_GPT_RESULT_STORE: HashValueStore = load_store("./tmp/rust_openai_gpt_tools_sled_db");
-   static ref RATE_LIMITER: Arc<Mutex<RateLimiter>> = Arc::new(Mutex::new(load_rate_limiter()));
+   static ref OPENAI_GPT_RESULT_STORE: HashValueStore = HashValueStore::new(&SLED_DB);
+   static ref BUDGET_ACCOUNTS: Arc<Mutex<BudgetAccounts>> = Arc::new(Mutex::new(load_budget_accounts()));
 }
 
-pub const GPT_4_8K_PRICE_PER_1K_TOKEN_COMPLETION: f64 = 0.06;
-pub const GPT_4_8K_PRICE_PER_1K_TOKEN_PROMPT: f64 = 0.03;
-pub const GPT_4_32K_PRICE_PER_1K_TOKEN_COMPLETION: f64 = 0.12;
-pub const GPT_4_32K_PRICE_PER_1K_TOKEN_PROMPT: f64 = 0.06;
+pub const DEFAULT_TEXT_COMPLETION_MODEL: &str = "gpt-3.5-turbo-instruct";
+
+
+pub fn load_budget_accounts() -> BudgetAccounts {
+    let config = load_budget_config(BUDGET_CONFIG_PATH);
+
+    for model in MODEL_REGISTRY.models() {
+        println!("{:?}/{}: price_per_1k_token (prompt): ${}, price_per_1k_token (completion): ${}",model.endpoints,model.name,model.pricing.prompt_per_1k_token,model.pricing.completion_per_1k_token);
+    }
+
+    println!("global: max_costs: ${} every {} seconds",config.global.max_costs,config.global.duration_secs);
+    for (name, limit) in config.projects.iter() {
+        println!("project '{}': max_costs: ${} every {} seconds",name,limit.max_costs,limit.duration_secs);
+    }
+    for (name, client) in config.clients.iter() {
+        println!("client '{}': {:?}",name,client);
+    }
+    if let Some(limit) = &config.default_client_limit {
+        println!("default per client: max_costs: ${} every {} seconds",limit.max_costs,limit.duration_secs);
+    }
 
-pub const DAVINCI_PRICE_PER_1K_TOKEN: f64 = 0.02;
-pub const GPT_3_5_TURBO_PRICE_PER_1K_TOKEN: f64 = 0.002;
-pub const ADA_EMBEDDING_PRICE_PER_1K_TOKEN: f64 = 0.0004;
+    BudgetAccounts::new(config)
+}
+
+pub fn load_store(path: &str) -> HashValueStore {
+    HashValueStore::new(&load_sled_db(path))
+}
 
+pub fn spawn_openai_gpt_api_socket_service(socket_path: &str) -> JoinHandle<()> {
+    println!("Starting OpenAI GPT API socket service at '{}'", socket_path);
+    let task = spawn_socket_service(socket_path,|bytes, peer_credentials| { process(bytes, peer_credentials)
+    });
+    println!("OpenAI GPT API socket service ready and listening for incoming connections.");
+    task
+}
 
 
-#[derive(Debug)]
-pub struct RateLimiter {
-    max_costs: f64,
-    duration: Duration,
-    remaining_budget: f64,
-    last_check: Instant,
+pub async fn moderated_text_completion_endpoint(model_name: &str, prompt: &str, completion_token_limit: u16, stop: &[String], moderation_mode: OpenAIGPTModerationMode) -> anyhow::Result<TextCompletion> {
+    moderated_text_completion_endpoint_with_backend(model_name, prompt, completion_token_limit, stop, moderation_mode, MODERATION_BACKEND.as_ref()).await
 }
 
-impl RateLimiter {
-    fn new(max_costs: f64, duration: Duration) -> Self {
-        RateLimiter {
-            max_costs,
-            duration,
-            remaining_budget: max_costs,
-            last_check: Instant::now(),
+pub async fn moderated_text_completion_endpoint_with_backend(model_name: &str, prompt: &str, completion_token_limit: u16, stop: &[String], moderation_mode: OpenAIGPTModerationMode, backend: &dyn ModerationBackend) -> anyhow::Result<TextCompletion> {
+    let mut redacted_prompt = None;
+    if moderation_mode.moderates_input() {
+        let verdict = backend.moderate(prompt).await?;
+        if let Some(rejection) = MODERATION_POLICY.rejection("TextCompletion", ModerationSide::Prompt, &verdict) {
+            return Err(rejection.into());
         }
+        redacted_prompt = verdict.redacted_text().map(|x| x.to_string());
     }
-
-    fn rate_limit(&mut self) -> bool {
-        let now = Instant::now();
-        if now.duration_since(self.last_check) > self.duration {
-            self.remaining_budget = self.max_costs;
-            self.last_check = now;
+    let prompt = redacted_prompt.as_deref().unwrap_or(prompt);
+    let mut completion = completion_endpoint_with_stop(model_name, prompt, completion_token_limit, stop).await?;
+    if let Some(output) = completion.choices.first().map(|x| x.text.to_owned()){
+        if !moderation_mode.moderates_output() {
+            return Ok(completion);
+        }
+        let verdict = backend.moderate(&output).await?;
+        if let Some(mut rejection) = MODERATION_POLICY.rejection("TextCompletion", ModerationSide::Result, &verdict) {
+            if MODERATION_POLICY.include_redacted_result {
+                rejection.redacted_result = Some(redact(verdict.redacted_text().unwrap_or(&output), &MODERATION_POLICY, backend).await?);
+            }
+            return Err(rejection.into());
+        }
+        if let Some(redacted_output) = verdict.redacted_text() {
+            completion.choices[0].text = redacted_output.to_string();
         }
-        println!("{:?}",&self);
-        self.remaining_budget > 0.0
+        Ok(completion)
+    }else{
+        Err(anyhow::anyhow!("Error: TextCompletion empty!"))
     }
+}
+
+pub async fn moderated_chat_completion_endpoint(model_name: &str, system: &str, prompt: &str, completion_token_limit: u16, stop: &[String], moderation_mode: OpenAIGPTModerationMode) -> anyhow::Result<ChatCompletion> {
+    moderated_chat_completion_endpoint_with_backend(model_name, system, prompt, completion_token_limit, stop, moderation_mode, MODERATION_BACKEND.as_ref()).await
+}
 
-    fn update_rate_limit(&mut self, tokens_used: u64, price_for_1k_token: f64) {
-        self.remaining_budget -= (tokens_used as f64 *price_for_1k_token)/1000.0;
-        println!("{:?}",&self);
+pub async fn moderated_chat_completion_endpoint_with_backend(model_name: &str, system: &str, prompt: &str, completion_token_limit: u16, stop: &[String], moderation_mode: OpenAIGPTModerationMode, backend: &dyn ModerationBackend) -> anyhow::Result<ChatCompletion> {
+    let mut redacted_prompt = None;
+    if moderation_mode.moderates_input() {
+        let verdict = backend.moderate(prompt).await?;
+        if let Some(rejection) = MODERATION_POLICY.rejection("ChatCompletion", ModerationSide::Prompt, &verdict) {
+            return Err(rejection.into());
+        }
+        redacted_prompt = verdict.redacted_text().map(|x| x.to_string());
+    }
+    let prompt = redacted_prompt.as_deref().unwrap_or(prompt);
+    let mut completion = chat_completion_endpoint_with_stop(model_name, system, prompt, completion_token_limit, stop).await?;
+    if let Some(output) = completion.choices.first().map(|x| x.message.content().to_string()){
+        if !moderation_mode.moderates_output() {
+            return Ok(completion);
+        }
+        let verdict = backend.moderate(&output).await?;
+        if let Some(mut rejection) = MODERATION_POLICY.rejection("ChatCompletion", ModerationSide::Result, &verdict) {
+            if MODERATION_POLICY.include_redacted_result {
+                rejection.redacted_result = Some(redact(verdict.redacted_text().unwrap_or(&output), &MODERATION_POLICY, backend).await?);
+            }
+            return Err(rejection.into());
+        }
+        if let Some(redacted_output) = verdict.redacted_text() {
+            completion.choices[0].message.content = Some(redacted_output.to_string());
+        }
+        Ok(completion)
+    }else{
+        Err(anyhow::anyhow!("Error: ChatCompletion empty!"))
     }
 }
 
+// a request's stop tokens override the default ones
+fn stop_tokens(stop: Option<&[String]>) -> anyhow::Result<Vec<String>> {
+    match stop {
+        Some(stop) => {
+            validate_stop_tokens(stop)?;
+            Ok(stop.to_vec())
+        }
+        None => Ok(default_stop_tokens()),
+    }
+}
 
-pub fn load_rate_limiter() -> RateLimiter {
-    // 25$ my upper price limit
-    let max_costs = 25.0;
-    let one_month = 60*60*24*30;
+fn moderation_rejection_result(rejection: &ModerationRejection, request: OpenAIGPTRequest) -> OpenAIGPTResult {
+    OpenAIGPTResult::ModerationRejectionResult(OpenAIGPTModerationRejection {
+        side: match rejection.side {
+            ModerationSide::Prompt => OpenAIGPTModerationSide::Prompt,
+            ModerationSide::Result => OpenAIGPTModerationSide::Result,
+        },
+        categories: rejection.violations.iter().map(|x| OpenAIGPTModerationCategory {
+            category: x.category.to_owned(),
+            score: x.score,
+            threshold: x.threshold,
+        }).collect(),
+        redacted_result: rejection.redacted_result.to_owned(),
+        request,
+    })
+}
 
-    println!("Embedding: price_per_1k_token: ${}",ADA_EMBEDDING_PRICE_PER_1K_TOKEN);
-    println!("TextCompletion: price_per_1k_token: ${}",DAVINCI_PRICE_PER_1K_TOKEN);
-    println!("ChatCompletion/GPT-3.5-turbo: price_per_1k_token: ${}",GPT_3_5_TURBO_PRICE_PER_1K_TOKEN);
-    println!("ChatCompletion/GPT-4_8k: price_per_1k_token (prompt): ${}",GPT_4_8K_PRICE_PER_1K_TOKEN_PROMPT);
-    println!("ChatCompletion/GPT-4_8k: price_per_1k_token (completion): ${}",GPT_4_8K_PRICE_PER_1K_TOKEN_COMPLETION);
-    println!("ChatCompletion/GPT-4_32k: price_per_1k_token (prompt): ${}",GPT_4_32K_PRICE_PER_1K_TOKEN_PROMPT);
-    println!("ChatCompletion/GPT-4_32k: price_per_1k_token (completion): ${}",GPT_4_32K_PRICE_PER_1K_TOKEN_COMPLETION);
 
-    println!("max_costs: ${}",max_costs);
-    println!("one_month: {} seconds",one_month);
+// applies the request's injection mode to the prompt, a classifier completion is paid from the client's budget
+async fn guard_prompt(client: &ClientIdentity, prompt: &str, mode: Option<OpenAIGPTInjectionMode>) -> anyhow::Result<GuardedPrompt> {
+    let guarded = INJECTION_GUARD.guard_prompt(prompt, mode.unwrap_or(INJECTION_GUARD.policy().default_mode)).await?;
+    if let Some(usage) = &guarded.classifier_usage {
+        update_rate_limit(client, &MODEL_REGISTRY.validate(&INJECTION_GUARD.policy().classifier_model, ModelEndpoint::Chat)?, usage)?;
+    }
+    Ok(guarded)
+}
 
-    RateLimiter::new(max_costs,Duration::from_secs(one_month))
+fn injection_report(report: &InjectionReport) -> OpenAIGPTInjectionReport {
+    OpenAIGPTInjectionReport {
+        score: report.score,
+        classifier_score: report.classifier_score,
+        markers: report.markers.to_owned(),
+        neutralized: report.neutralized,
+    }
 }
 
-pub fn load_store(path: &str) -> HashValueStore {
-    let db: sled::Db = sled::Config::default()
-        .path(path)
-        .cache_capacity( 1024 * 1024 * 1024) // 1gb
-        //.use_compression(true)
-        //.compression_factor(22)
-        .flush_every_ms(Some(100))
-        .open()
-        .unwrap();
-    HashValueStore::new(&db)
+pub async fn process(bytes: Vec<u8>, peer_credentials: Option<PeerCredentials>) -> anyhow::Result<Vec<u8>> {
+
+    let mut request: OpenAIGPTRequest = bytes.try_into()?;
+
+    let mut claimed_identity = None;
+    while let OpenAIGPTRequest::ClientRequest(client_request) = request {
+        if claimed_identity.is_none() {
+            claimed_identity = Some((client_request.client_id, client_request.project));
+        }
+        request = *client_request.request;
+    }
+    let client = match BUDGET_ACCOUNTS.lock() {
+        Ok(o) => { o.verify_identity(peer_credentials.map(|x| x.uid), claimed_identity)? }
+        Err(_) => { return Err(anyhow::anyhow!("Error: Budget accounts unavailable!")); }
+    };
+
+    let result = process_request(&client, request).await?;
+
+    let into_bytes: Vec<u8> = result.try_into()?;
+    Ok(into_bytes)
 }
 
-pub fn spawn_openai_gpt_api_socket_service(socket_path: &str) -> JoinHandle<()> {
-    println!("Starting OpenAI GPT API socket service at '{}'", socket_path);
-    let task = spawn_socket_service(socket_path,|bytes| { process(bytes)
-    });
-    println!("OpenAI GPT API socket service ready and listening for incoming connections.");
-    task
+// fails if the request could cost more than what is left of the client's budget
+pub fn check_budget(client: &ClientIdentity, model: &ModelInfo, prompt_tokens: usize, completion_token_limit: u16) -> anyhow::Result<()> {
+    let estimated_costs = model.costs(prompt_tokens as u64, completion_token_limit as u64);
+    let remaining_budget = match BUDGET_ACCOUNTS.lock() {
+        Ok(ref mut o) => { o.remaining_budget(client) }
+        Err(_) => { 0.0 }
+    };
+    if estimated_costs > remaining_budget {
+        return Err(BudgetExceeded { model_name: model.name.to_owned(), estimated_costs, remaining_budget }.into());
+    }
+    Ok(())
 }
 
+pub fn update_rate_limit(client: &ClientIdentity, model: &ModelInfo, usage: &Usage) -> anyhow::Result<()> {
+    match BUDGET_ACCOUNTS.lock() {
+        Ok(ref mut o) => { o.update_rate_limit(client, model.usage_costs(usage)) }
+        Err(_) => { return Err(anyhow::anyhow!("Error: Budget accounts unavailable!")); }
+    };
+    Ok(())
+}
 
-pub async fn moderated_text_completion_endpoint(prompt: &str, completion_token_limit: u16) -> anyhow::Result<TextCompletion> {
-    if moderation_endpoint(prompt).await?.results.iter().filter(|x| x.flagged).count() == 0 {
-        let completion = completion_endpoint(prompt,completion_token_limit).await?;
-        if let Some(output) = completion.choices.first().map(|x| x.text.to_owned()){
-            if super::moderation::moderation_endpoint(&output).await?.results.iter().filter(|x| x.flagged).count() == 0 {
-                return Ok(completion);
-            }else{
-                Err(anyhow::anyhow!("Error: TextCompletion result unsafe!"))
+// charges the calls a failed request was billed for before it failed, the error is passed on
+pub fn charge_partially_billed(client: &ClientIdentity, model: &ModelInfo, err: anyhow::Error) -> anyhow::Error {
+    if let Some(billed) = err.downcast_ref::<PartiallyBilled>() {
+        for usage in billed.usages.iter() {
+            if let Err(charge_err) = update_rate_limit(client, model, usage) {
+                return charge_err;
             }
-        }else{
-            Err(anyhow::anyhow!("Error: TextCompletion empty!"))
         }
-    }else{
-        Err(anyhow::anyhow!("Error: TextCompletion prompt unsafe!"))
     }
+    err
 }
 
-pub async fn moderated_chat_completion_endpoint(model_name: &str, system: &str, prompt: &str, completion_token_limit: u16) -> anyhow::Result<ChatCompletion> {
-    if moderation_endpoint(prompt).await?.results.iter().filter(|x| x.flagged).count() == 0 {
-        let completion = chat_completion_endpoint(model_name, system, prompt, completion_token_limit).await?;
-        if let Some(output) = completion.choices.first().map(|x| x.message.content.to_owned()){
-            if super::moderation::moderation_endpoint(&output).await?.results.iter().filter(|x| x.flagged).count() == 0 {
-                return Ok(completion);
-            }else{
-                Err(anyhow::anyhow!("Error: ChatCompletion result unsafe!"))
-            }
-        }else{
-            Err(anyhow::anyhow!("Error: ChatCompletion empty!"))
+// replaces the requested moderation mode by the one the policy allows for this client, before the request is hashed
+fn apply_moderation_policy(client: &ClientIdentity, request: OpenAIGPTRequest) -> OpenAIGPTRequest {
+    match request {
+        OpenAIGPTRequest::ChatCompletionRequest(mut request) => {
+            request.moderation = Some(MODERATION_POLICY.effective_mode(client, request.moderation));
+            OpenAIGPTRequest::ChatCompletionRequest(request)
         }
-    }else{
-        Err(anyhow::anyhow!("Error: ChatCompletion prompt unsafe!"))
+        OpenAIGPTRequest::TextCompletionRequest(mut request) => {
+            request.moderation = Some(MODERATION_POLICY.effective_mode(client, request.moderation));
+            OpenAIGPTRequest::TextCompletionRequest(request)
+        }
+        request => request,
+    }
+}
+
+async fn process_embedding_request(client: &ClientIdentity, request: OpenAIGPTEmbeddingRequest) -> anyhow::Result<OpenAIGPTResult> {
+    let model = MODEL_REGISTRY.validate(request.model_name.as_deref().unwrap_or(DEFAULT_EMBEDDING_MODEL), ModelEndpoint::Embedding)?;
+    match BUDGET_ACCOUNTS.lock() {
+        Ok(ref mut o) => { o.rate_limit(client)?; }
+        Err(_) => { return Err(anyhow::anyhow!("Error: Rate Exceeded!")); }
+    };
+    // cached texts are not billed, counting all of them errs on the safe side
+    check_budget(client, &model, request.texts.iter().map(|x| count_tokens(&model.name, x)).sum(), 0)?;
+    let (embeddings, usage) = cached_embedding_endpoint(&model.name, &request.texts, request.dimensions, request.encoding_format.unwrap_or_default()).await
+        .map_err(|err| anyhow::anyhow!(charge_partially_billed(client, &model, err).to_string()))?;
+    if let Some(usage) = usage {
+        update_rate_limit(client, &model, &usage)?;
+    }
+    Ok(OpenAIGPTResult::EmbeddingResult(OpenAIGPTEmbeddingResult {
+        result: embeddings,
+        request,
+    }))
+}
+
+fn throttle_status_result() -> OpenAIGPTResult {
+    OpenAIGPTResult::ThrottleStatusResult(OpenAIGPTThrottleStatusResult {
+        models: throttle_status().into_iter().map(|x| OpenAIGPTModelThrottleStatus {
+            model_name: x.model_name,
+            requests_per_minute: x.requests_per_minute,
+            tokens_per_minute: x.tokens_per_minute,
+            available_requests: x.available_requests,
+            available_tokens: x.available_tokens,
+            learned_from_headers: x.learned_from_headers,
+            blocked_for_ms: x.blocked_for_ms,
+        }).collect(),
+    })
+}
+
+// the session's history is kept per client, a rejected message or answer leaves the session unchanged
+// sessions belong to a verified identity, anyone could claim an unverified one
+async fn process_chat_session_request(client: &ClientIdentity, request: OpenAIGPTChatSessionRequest) -> anyhow::Result<OpenAIGPTResult> {
+    if !client.verified {
+        return Err(anyhow::anyhow!("Error: chat sessions need a verified client identity"));
+    }
+    let model = MODEL_REGISTRY.validate(&CONVERSATION_CONFIG.model_name, ModelEndpoint::Chat)?;
+    match BUDGET_ACCOUNTS.lock() {
+        Ok(ref mut o) => { o.rate_limit(client)?; }
+        Err(_) => { return Err(anyhow::anyhow!("Error: Rate Exceeded!")); }
+    };
+    let moderation_mode = MODERATION_POLICY.effective_mode(client, None);
+    let mut message = request.message.to_owned();
+    if moderation_mode.moderates_input() {
+        let verdict = MODERATION_BACKEND.moderate(&request.message).await?;
+        if let Some(rejection) = MODERATION_POLICY.rejection("ChatSession", ModerationSide::Prompt, &verdict) {
+            return Ok(moderation_rejection_result(&rejection, OpenAIGPTRequest::ChatSessionRequest(request)));
+        }
+        if let Some(redacted_message) = verdict.redacted_text() {
+            message = redacted_message.to_string();
+        }
+    }
+
+    let session_id = format!("{}/{}/{}", client.client_id, client.project.as_deref().unwrap_or(""), request.session_id);
+    let _session_lock = lock_session(&session_id).await;
+    let mut conversation = Conversation::open(&session_id, CONVERSATION_CONFIG.clone())?;
+    conversation.push("user", &message);
+    // the answer's prompt is at most the history that fits
+    check_budget(client, &model, conversation.tokens().min(conversation.context_tokens()), CONVERSATION_CONFIG.completion_token_limit)?;
+    // the summaries are paid for, so they are kept even if there is no answer
+    let usages = match conversation.fit().await {
+        Ok(usages) => usages,
+        Err(err) => {
+            let err = charge_partially_billed(client, &model, err);
+            conversation.discard_exchange();
+            conversation.save()?;
+            return Err(err);
+        }
+    };
+    for usage in usages.iter() {
+        update_rate_limit(client, &model, usage)?;
+    }
+    // the summaries may have used up what the check above left
+    let answered = match check_budget(client, &model, conversation.tokens(), CONVERSATION_CONFIG.completion_token_limit) {
+        Ok(()) => conversation.answer().await,
+        Err(err) => Err(err),
+    };
+    let answer = match answered {
+        Ok((answer, usage)) => {
+            update_rate_limit(client, &model, &usage)?;
+            answer
+        }
+        Err(err) => {
+            conversation.discard_exchange();
+            conversation.save()?;
+            return Err(err);
+        }
+    };
+    if moderation_mode.moderates_output() {
+        let verdict = MODERATION_BACKEND.moderate(&answer).await?;
+        if let Some(rejection) = MODERATION_POLICY.rejection("ChatSession", ModerationSide::Result, &verdict) {
+            conversation.discard_exchange();
+            conversation.save()?;
+            return Ok(moderation_rejection_result(&rejection, OpenAIGPTRequest::ChatSessionRequest(request)));
+        }
+    }
+    conversation.save()?;
+
+    Ok(OpenAIGPTResult::ChatSessionResult(OpenAIGPTChatSessionResult {
+        result: answer,
+        model_name: model.name,
+        turns: conversation.state().turns.len() as u32,
+        request,
+    }))
+}
+
+async fn process_fraud_classification_request(client: &ClientIdentity, request: OpenAIGPTFraudClassificationRequest) -> anyhow::Result<OpenAIGPTResult> {
+    let model = MODEL_REGISTRY.validate(request.model_name.as_deref().unwrap_or(&FRAUD_DETECTOR.config().model_name), ModelEndpoint::Chat)?;
+    let (verdict, usage) = FRAUD_DETECTOR.classify(&request.text, Some(&model.name), |prompt_tokens| {
+        match BUDGET_ACCOUNTS.lock() {
+            Ok(ref mut o) => { o.rate_limit(client)?; }
+            Err(_) => { return Err(anyhow::anyhow!("Error: Rate Exceeded!")); }
+        };
+        check_budget(client, &model, prompt_tokens, FRAUD_DETECTOR.config().completion_token_limit)
+    }).await.map_err(|err| charge_partially_billed(client, &model, err))?;
+    if let Some(usage) = usage {
+        update_rate_limit(client, &model, &usage)?;
     }
+    Ok(OpenAIGPTResult::FraudClassificationResult(OpenAIGPTFraudClassificationResult {
+        label: match verdict.label {
+            FraudLabel::Legitimate => OpenAIGPTFraudLabel::Legitimate,
+            FraudLabel::Suspicious => OpenAIGPTFraudLabel::Suspicious,
+            FraudLabel::Fraud => OpenAIGPTFraudLabel::Fraud,
+        },
+        confidence: verdict.confidence,
+        reasons: verdict.reasons,
+        offending_urls: verdict.offending_urls,
+        model_name: verdict.model_name,
+        request,
+    }))
 }
 
+pub async fn process_request(client: &ClientIdentity, request: OpenAIGPTRequest) -> anyhow::Result<OpenAIGPTResult> {
 
-pub async fn process(bytes: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let request = apply_moderation_policy(client, request);
 
-    let request: OpenAIGPTRequest = bytes.try_into()?;
+    let request = match request {
+        OpenAIGPTRequest::ChatCompletionRequest(_) | OpenAIGPTRequest::TextCompletionRequest(_) => request,
+        // embeddings are cached per text instead, so partially overlapping requests still hit the cache
+        OpenAIGPTRequest::EmbeddingRequest(request) => return process_embedding_request(client, request).await,
+        // sessions change with every message, so they are never cached
+        OpenAIGPTRequest::ChatSessionRequest(request) => return process_chat_session_request(client, request).await,
+        // the throttle state changes with every request, so it is never cached either
+        OpenAIGPTRequest::ThrottleStatusRequest => return Ok(throttle_status_result()),
+        // the fraud detector caches its verdicts itself, keyed by its configuration as well
+        OpenAIGPTRequest::FraudClassificationRequest(request) => return process_fraud_classification_request(client, request).await,
+        OpenAIGPTRequest::ClientRequest(client_request) => return Box::pin(process_request(client, *client_request.request)).await,
+    };
 
-    let hash = request.get_hash();
+    // results are cached per client, so every client pays for its own completions
+    let hash = digest(&(request.get_hash(), client));
 
     let result;
 
     if OPENAI_GPT_RESULT_STORE.contains_hash(hash)? {
         result = OPENAI_GPT_RESULT_STORE.get_item_by_hash::<OpenAIGPTResult>(hash)?.unwrap();
     } else {
-        let rate_limit = match RATE_LIMITER.lock() {
-            Ok(ref mut o) => { o.rate_limit() }
-            Err(_) => { false }
+        match BUDGET_ACCOUNTS.lock() {
+            Ok(ref mut o) => { o.rate_limit(client)?; }
+            Err(_) => { return Err(anyhow::anyhow!("Error: Rate Exceeded!")); }
         };
-        if rate_limit {
-            match request {
-                OpenAIGPTRequest::ChatCompletionRequest(request) => {
-                    result = OpenAIGPTResult::ChatCompletionResult(OpenAIGPTChatCompletionResult {
-                        result:
-                        match moderated_chat_completion_endpoint(request.model_name.as_str(),request.system.as_str(),request.prompt.as_str(), request.completion_token_limit).await {
-                            Ok(completion) => {
-                                match RATE_LIMITER.lock() {
-                                    Ok(ref mut o) => {
-                                        match request.model_name.as_str() {
-                                            "gpt-4" => {
-                                                o.update_rate_limit(completion.usage.prompt_tokens as u64,GPT_4_8K_PRICE_PER_1K_TOKEN_PROMPT);
-                                                o.update_rate_limit(completion.usage.completion_tokens.unwrap_or(0i64) as u64,GPT_4_8K_PRICE_PER_1K_TOKEN_COMPLETION);
-                                            },
-                                            "gpt-4-32k" => {
-                                                o.update_rate_limit(completion.usage.prompt_tokens as u64,GPT_4_32K_PRICE_PER_1K_TOKEN_PROMPT);
-                                                o.update_rate_limit(completion.usage.completion_tokens.unwrap_or(0i64) as u64,GPT_4_32K_PRICE_PER_1K_TOKEN_COMPLETION);
-                                            },
-                                            "gpt-3.5-turbo" => {
-                                                o.update_rate_limit(completion.usage.total_tokens as u64,GPT_3_5_TURBO_PRICE_PER_1K_TOKEN)
-                                            },
-                                            _ => {
-                                                panic!()
-                                            }
-                                        }
-                                    }
-                                    Err(_) => { panic!() }
-                                };
-                                completion.choices.first().map(|x| x.message.content.to_owned()).unwrap_or("".to_string())
-                            }
-                            Err(err) => {
-                                return Err(anyhow::anyhow!(err.to_string()));
-                            }
-                        },
-                        request: request,
-                    });
-                }
-                OpenAIGPTRequest::TextCompletionRequest(request) => {
-                    result = OpenAIGPTResult::TextCompletionResult(OpenAIGPTTextCompletionResult {
-                        result:
-                        match moderated_text_completion_endpoint(request.prompt.as_str(), request.completion_token_limit).await {
-                            Ok(completion) => {
-                                match RATE_LIMITER.lock() {
-                                    Ok(ref mut o) => { o.update_rate_limit(completion.usage.total_tokens as u64,DAVINCI_PRICE_PER_1K_TOKEN) }
-                                    Err(_) => { panic!() }
-                                };
-                                completion.choices.first().map(|x| x.text.to_owned()).unwrap_or("".to_string())
-                            }
-                            Err(err) => {
-                                return Err(anyhow::anyhow!(err.to_string()));
-                            }
-                        },
-                        request: request,
-                    });
-                }
-                OpenAIGPTRequest::EmbeddingRequest(request) => {
-                    result = OpenAIGPTResult::EmbeddingResult(OpenAIGPTEmbeddingResult {
-                        result:
-                        match embedding_endpoint(request.texts.clone()).await {
-                            Ok(embedding_data) => {
-                                match RATE_LIMITER.lock() {
-                                    Ok(ref mut o) => { o.update_rate_limit(embedding_data.usage.total_tokens as u64,ADA_EMBEDDING_PRICE_PER_1K_TOKEN) }
-                                    Err(_) => { panic!() }
-                                };
-                                embedding_data.data.into_iter().map(|x| x.embedding).collect::<Vec<Vec<f32>>>()
-                            }
-                            Err(err) => {
-                                return Err(anyhow::anyhow!(err.to_string()));
-                            }
-                        },
-                        request: request,
-                    });
-                }
+        match request {
+            OpenAIGPTRequest::ChatCompletionRequest(request) => {
+                let remaining_budget_fraction = match BUDGET_ACCOUNTS.lock() {
+                    Ok(ref mut o) => { o.remaining_budget_fraction(client) }
+                    Err(_) => { 0.0 }
+                };
+                let stop = stop_tokens(request.stop.as_deref())?;
+                let guarded = guard_prompt(client, &request.prompt, request.injection).await?;
+                let candidates = candidate_models(&request.model_name, request.routing.as_ref(), remaining_budget_fraction);
+                let fallback_on = request.routing.as_ref().map(|x| x.fallback_on.clone()).unwrap_or_default();
+                let routed = route(candidates, ModelEndpoint::Chat, &fallback_on, |model| {
+                    let (request, prompt, stop) = (&request, &guarded.prompt, &stop);
+                    async move {
+                        check_budget(client, &model, count_chat_tokens(&model.name, &[("system", &request.system), ("user", prompt)]), request.completion_token_limit)?;
+                        moderated_chat_completion_endpoint(model.name.as_str(),request.system.as_str(),prompt.as_str(), request.completion_token_limit, stop, request.moderation.unwrap_or(MODERATION_POLICY.default_mode)).await
+                    }
+                }).await;
+                result = match routed {
+                    Ok((model, completion)) => {
+                        update_rate_limit(client, &model, &completion.usage)?;
+                        OpenAIGPTResult::ChatCompletionResult(OpenAIGPTChatCompletionResult {
+                            result: completion.choices.first().map(|x| x.message.content().to_string()).unwrap_or("".to_string()),
+                            model_name: model.name,
+                            injection: guarded.report.as_ref().map(injection_report),
+                            request,
+                        })
+                    }
+                    Err(err) => match err.downcast_ref::<ModerationRejection>() {
+                        Some(rejection) => moderation_rejection_result(rejection, OpenAIGPTRequest::ChatCompletionRequest(request)),
+                        None => return Err(anyhow::anyhow!(err.to_string())),
+                    }
+                };
             }
-            OPENAI_GPT_RESULT_STORE.insert_item(hash, result.clone()).ok();
-        }else {
-                return Err(anyhow::anyhow!("Error: Rate Exceeded!"));
+            OpenAIGPTRequest::TextCompletionRequest(request) => {
+                let model = MODEL_REGISTRY.validate(request.model_name.as_deref().unwrap_or(DEFAULT_TEXT_COMPLETION_MODEL), ModelEndpoint::Completion)?;
+                let stop = stop_tokens(request.stop.as_deref())?;
+                let guarded = guard_prompt(client, &request.prompt, request.injection).await?;
+                check_budget(client, &model, count_tokens(&model.name, &guarded.prompt), request.completion_token_limit)?;
+                result = match moderated_text_completion_endpoint(model.name.as_str(), guarded.prompt.as_str(), request.completion_token_limit, &stop, request.moderation.unwrap_or(MODERATION_POLICY.default_mode)).await {
+                    Ok(completion) => {
+                        update_rate_limit(client, &model, &completion.usage)?;
+                        OpenAIGPTResult::TextCompletionResult(OpenAIGPTTextCompletionResult {
+                            result: completion.choices.first().map(|x| x.text.to_owned()).unwrap_or("".to_string()),
+                            injection: guarded.report.as_ref().map(injection_report),
+                            request,
+                        })
+                    }
+                    Err(err) => match err.downcast_ref::<ModerationRejection>() {
+                        Some(rejection) => moderation_rejection_result(rejection, OpenAIGPTRequest::TextCompletionRequest(request)),
+                        None => return Err(anyhow::anyhow!(err.to_string())),
+                    }
+                };
+            }
+            // answered above
+            _ => return Err(anyhow::anyhow!("Error: unexpected request")),
         }
+        OPENAI_GPT_RESULT_STORE.insert_item(hash, result.clone()).ok();
     };
 
-    let into_bytes: Vec<u8> = result.try_into()?;
-    Ok(into_bytes)
+    Ok(result)
 }
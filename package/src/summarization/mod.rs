@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use rust_openai_gpt_tools_socket_ipc::ipc::{OpenAIGPTChatCompletionRequest, OpenAIGPTRequest, OpenAIGPTResult};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::budget::ClientIdentity;
+use crate::moderation::chunk_text;
+use crate::prompt::escape;
+use crate::service::process_request;
+use crate::tokenizer::count_tokens;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SummarizationConfig {
+    pub model_name: String,
+    pub system: String,
+    // instruction for the summary of each chunk
+    pub map_prompt: String,
+    // instruction for combining summaries
+    pub reduce_prompt: String,
+    pub chunk_tokens: usize,
+    pub summary_token_limit: u16,
+    // combining stops once the summary is at most this long
+    pub target_tokens: usize,
+    pub max_depth: usize,
+    pub concurrency: usize,
+}
+
+impl Default for SummarizationConfig {
+    fn default() -> Self {
+        SummarizationConfig {
+            model_name: "gpt-3.5-turbo".to_string(),
+            system: "You summarize governance proposals. Attributes: helpful, expert, truthful.".to_string(),
+            map_prompt: "Summarize the following".to_string(),
+            reduce_prompt: "Combine the following summaries of parts of one document into a single summary".to_string(),
+            chunk_tokens: 2_000,
+            summary_token_limit: 300,
+            target_tokens: 300,
+            max_depth: 5,
+            concurrency: 4,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Summary {
+    pub summary: String,
+    // the number of reduce rounds, 0 if the text fit into one chunk
+    pub depth: usize,
+    pub completions: usize,
+}
+
+// paragraphs packed into chunks of at most `max_tokens`, paragraphs that are too long are cut at sentences
+pub fn split_paragraphs(model_name: &str, text: &str, max_tokens: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut tokens = 0;
+    for paragraph in text.split("\n\n").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let paragraph_tokens = count_tokens(model_name, paragraph);
+        let pieces = if paragraph_tokens > max_tokens {
+            chunk_text(model_name, paragraph, max_tokens, 0).into_iter().map(|(start, end)| paragraph[start..end].trim().to_string()).collect::<Vec<String>>()
+        } else {
+            vec![paragraph.to_string()]
+        };
+        for piece in pieces {
+            let piece_tokens = count_tokens(model_name, &piece);
+            if !chunk.is_empty() && tokens + piece_tokens > max_tokens {
+                chunks.push(std::mem::take(&mut chunk));
+                tokens = 0;
+            }
+            if !chunk.is_empty() {
+                chunk.push_str("\n\n");
+            }
+            chunk.push_str(&piece);
+            tokens += piece_tokens;
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
+// one completion through the service, cached and paid from the client's budget
+async fn complete(client: &ClientIdentity, config: &SummarizationConfig, instruction: &str, source: &str) -> anyhow::Result<String> {
+    let request = OpenAIGPTRequest::ChatCompletionRequest(OpenAIGPTChatCompletionRequest {
+        model_name: config.model_name.to_owned(),
+        system: config.system.to_owned(),
+        prompt: format!("<instruction>{}</instruction><source>{}</source>\n\n<result>", instruction, escape(source)),
+        completion_token_limit: config.summary_token_limit,
+        routing: None,
+        moderation: None,
+        injection: None,
+        stop: None,
+    });
+    match process_request(client, request).await? {
+        OpenAIGPTResult::ChatCompletionResult(result) => Ok(result.result.trim().to_string()),
+        OpenAIGPTResult::ModerationRejectionResult(rejection) => Err(anyhow::anyhow!("Error: summary rejected by moderation: {:?}", rejection.categories)),
+        result => Err(anyhow::anyhow!("Error: unexpected result {:?}", result)),
+    }
+}
+
+// summarizes the sources concurrently, at most `config.concurrency` at a time, in input order
+// on the first error the remaining tasks are aborted when the set is dropped
+async fn complete_all(client: &ClientIdentity, config: &SummarizationConfig, instruction: &str, sources: Vec<String>) -> anyhow::Result<Vec<String>> {
+    let semaphore = Arc::new(Semaphore::new(config.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let count = sources.len();
+    for (i, source) in sources.into_iter().enumerate() {
+        let (semaphore, client, config, instruction) = (semaphore.clone(), client.clone(), config.clone(), instruction.to_string());
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            Ok::<_, anyhow::Error>((i, complete(&client, &config, &instruction, &source).await?))
+        });
+    }
+    let mut results = vec![String::new(); count];
+    while let Some(joined) = tasks.join_next().await {
+        let (i, summary) = joined??;
+        results[i] = summary;
+    }
+    Ok(results)
+}
+
+// map: every chunk is summarized, reduce: the summaries are combined in groups that fit a chunk until one short enough is left
+pub async fn summarize(client: &ClientIdentity, text: &str, config: &SummarizationConfig) -> anyhow::Result<Summary> {
+    let chunks = split_paragraphs(&config.model_name, text, config.chunk_tokens);
+    if chunks.is_empty() {
+        return Err(anyhow::anyhow!("Error: nothing to summarize"));
+    }
+    let mut completions = chunks.len();
+    let mut summaries = complete_all(client, config, &config.map_prompt, chunks).await?;
+    let mut depth = 0;
+    loop {
+        // blank summaries leave nothing to reduce
+        if summaries.is_empty() {
+            return Err(anyhow::anyhow!("Error: no summary left after {} rounds", depth));
+        }
+        if summaries.len() == 1 && count_tokens(&config.model_name, &summaries[0]) <= config.target_tokens {
+            break;
+        }
+        if depth >= config.max_depth {
+            if summaries.len() > 1 {
+                return Err(anyhow::anyhow!("Error: {} summaries left after {} rounds", summaries.len(), depth));
+            }
+            break;
+        }
+        let groups = split_paragraphs(&config.model_name, &summaries.join("\n\n"), config.chunk_tokens);
+        completions += groups.len();
+        summaries = complete_all(client, config, &config.reduce_prompt, groups).await?;
+        depth += 1;
+    }
+    Ok(Summary { summary: summaries.remove(0), depth, completions })
+}
@@ -1,7 +1,7 @@
 
-use reqwest::Client;
-use reqwest::header::HeaderValue;
-use reqwest::header::CONTENT_TYPE;
+use crate::api::post_json;
+use crate::tokenizer::{count_tokens, clamp_completion_tokens};
+use crate::prompt::default_stop_tokens;
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct TextCompletion {
@@ -22,31 +22,33 @@ pub struct Choice {
 }
 
 
-pub async fn completion_endpoint(prompt: &str, completion_token_limit: u16) -> anyhow::Result<TextCompletion> {
+pub async fn completion_endpoint(model_name: &str, prompt: &str, completion_token_limit: u16) -> anyhow::Result<TextCompletion> {
+    completion_endpoint_with_stop(model_name, prompt, completion_token_limit, &default_stop_tokens()).await
+}
+
+pub async fn completion_endpoint_with_stop(model_name: &str, prompt: &str, completion_token_limit: u16, stop: &[String]) -> anyhow::Result<TextCompletion> {
 
-    let json_data = serde_json::json!({
-                "model": "text-davinci-003",
+    let prompt_tokens = count_tokens(model_name, prompt);
+    let max_tokens = clamp_completion_tokens(model_name, prompt_tokens, completion_token_limit)?;
+
+    let mut json_data = serde_json::json!({
+                "model": model_name, // "gpt-3.5-turbo-instruct"
                 "prompt": prompt,
-                "max_tokens": if completion_token_limit > super::MAX_TOKENS { super::MAX_TOKENS } else{ completion_token_limit },
+                "max_tokens": max_tokens,
                 "temperature": 0,
                 "presence_penalty": 1.0,
                 "frequency_penalty": 1.0,
                 "top_p": 1,
                 "n": 1,
-                "stop": ["<result","<result>","</result>"]
               });
+    // OpenAI rejects an empty list of stop sequences
+    if !stop.is_empty() {
+        json_data["stop"] = serde_json::json!(stop);
+    }
 
     //println!("{:?}",&json_data);
 
-    let client = Client::new();
-    let url = "https://api.openai.com/v1/completions";
-    let response = client.post(url)
-        .bearer_auth(&super::ENV.openai_api_key)
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(json_data.to_string())
-        .send().await;
-
-    let completion = response?.json::<TextCompletion>().await?;
+    let completion = post_json::<TextCompletion>("https://api.openai.com/v1/completions", model_name, (prompt_tokens + max_tokens as usize) as u64, &json_data).await?;
 
     Ok(completion)
 }
\ No newline at end of file
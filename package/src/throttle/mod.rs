@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::models::MODEL_REGISTRY;
+
+lazy_static!{
+   static ref THROTTLE: Mutex<Throttle> = Mutex::new(Throttle::default());
+}
+
+// the registry's conservative defaults until OpenAI reports the actual limits
+pub fn default_limits(model_name: &str) -> (u64, u64) {
+    match MODEL_REGISTRY.get(model_name).and_then(|x| x.rate_limits) {
+        Some(limits) => (limits.requests_per_minute, limits.tokens_per_minute),
+        None => (60, 40_000),
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn per_minute(capacity: u64) -> Self {
+        TokenBucket {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            refill_per_sec: capacity as f64 / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // returns how long to wait until `amount` is available
+    fn wait_time(&mut self, amount: f64) -> Duration {
+        self.refill();
+        let amount = amount.min(self.capacity);
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) / self.refill_per_sec)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+
+    fn set_capacity(&mut self, capacity: u64) {
+        self.refill();
+        self.capacity = capacity as f64;
+        self.refill_per_sec = capacity as f64 / 60.0;
+        self.available = self.available.min(self.capacity);
+    }
+
+    fn set_remaining(&mut self, remaining: u64) {
+        self.refill();
+        self.available = self.available.min(remaining as f64);
+    }
+}
+
+#[derive(Debug)]
+struct ModelThrottle {
+    requests: TokenBucket,
+    tokens: TokenBucket,
+    learned_from_headers: bool,
+    blocked_until: Option<Instant>,
+}
+
+impl ModelThrottle {
+    fn new(model_name: &str) -> Self {
+        let (requests_per_minute, tokens_per_minute) = default_limits(model_name);
+        ModelThrottle {
+            requests: TokenBucket::per_minute(requests_per_minute),
+            tokens: TokenBucket::per_minute(tokens_per_minute),
+            learned_from_headers: false,
+            blocked_until: None,
+        }
+    }
+
+    fn wait_time(&mut self, tokens: u64) -> Duration {
+        let blocked = self.blocked_until
+            .map(|x| x.saturating_duration_since(Instant::now()))
+            .unwrap_or(Duration::ZERO);
+        blocked
+            .max(self.requests.wait_time(1.0))
+            .max(self.tokens.wait_time(tokens as f64))
+    }
+
+    fn block_for(&mut self, duration: Duration) {
+        let until = Instant::now() + duration;
+        if self.blocked_until.map(|x| x < until).unwrap_or(true) {
+            self.blocked_until = Some(until);
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelThrottleStatus {
+    pub model_name: String,
+    pub requests_per_minute: u64,
+    pub tokens_per_minute: u64,
+    pub available_requests: u64,
+    pub available_tokens: u64,
+    pub learned_from_headers: bool,
+    pub blocked_for_ms: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Throttle {
+    models: HashMap<String, ModelThrottle>,
+}
+
+impl Throttle {
+    fn model(&mut self, model_name: &str) -> &mut ModelThrottle {
+        self.models.entry(model_name.to_string()).or_insert_with(|| ModelThrottle::new(model_name))
+    }
+
+    // takes the capacity if available, otherwise returns how long to wait before trying again
+    pub fn try_acquire(&mut self, model_name: &str, tokens: u64) -> Option<Duration> {
+        let model = self.model(model_name);
+        let wait_time = model.wait_time(tokens);
+        if wait_time.is_zero() {
+            model.requests.take(1.0);
+            model.tokens.take(tokens as f64);
+            None
+        } else {
+            Some(wait_time)
+        }
+    }
+
+    pub fn observe_response(&mut self, model_name: &str, status: u16, headers: &HeaderMap) {
+        let model = self.model(model_name);
+
+        if let Some(limit) = header_u64(headers, "x-ratelimit-limit-requests") {
+            model.requests.set_capacity(limit);
+            model.learned_from_headers = true;
+        }
+        if let Some(limit) = header_u64(headers, "x-ratelimit-limit-tokens") {
+            model.tokens.set_capacity(limit);
+            model.learned_from_headers = true;
+        }
+        if let Some(remaining) = header_u64(headers, "x-ratelimit-remaining-requests") {
+            model.requests.set_remaining(remaining);
+            if remaining == 0 {
+                if let Some(reset) = header_duration(headers, "x-ratelimit-reset-requests") {
+                    model.block_for(reset);
+                }
+            }
+        }
+        if let Some(remaining) = header_u64(headers, "x-ratelimit-remaining-tokens") {
+            model.tokens.set_remaining(remaining);
+            if remaining == 0 {
+                if let Some(reset) = header_duration(headers, "x-ratelimit-reset-tokens") {
+                    model.block_for(reset);
+                }
+            }
+        }
+        if status == 429 {
+            let retry_after = header_u64(headers, "retry-after").map(Duration::from_secs)
+                .or_else(|| header_duration(headers, "x-ratelimit-reset-tokens"))
+                .or_else(|| header_duration(headers, "x-ratelimit-reset-requests"))
+                .unwrap_or(Duration::from_secs(1));
+            model.block_for(retry_after);
+            println!("Throttle: '{}' rate limited by OpenAI, backing off for {:?}", model_name, retry_after);
+        }
+    }
+
+    pub fn status(&mut self) -> Vec<ModelThrottleStatus> {
+        let mut status = self.models.iter_mut().map(|(model_name, model)| {
+            model.requests.refill();
+            model.tokens.refill();
+            ModelThrottleStatus {
+                model_name: model_name.to_owned(),
+                requests_per_minute: model.requests.capacity as u64,
+                tokens_per_minute: model.tokens.capacity as u64,
+                available_requests: model.requests.available.max(0.0) as u64,
+                available_tokens: model.tokens.available.max(0.0) as u64,
+                learned_from_headers: model.learned_from_headers,
+                blocked_for_ms: model.blocked_until
+                    .map(|x| x.saturating_duration_since(Instant::now()).as_millis() as u64)
+                    .unwrap_or(0),
+            }
+        }).collect::<Vec<ModelThrottleStatus>>();
+        status.sort_by(|a, b| a.model_name.cmp(&b.model_name));
+        status
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse::<u64>().ok()
+}
+
+fn header_duration(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    parse_duration(headers.get(name)?.to_str().ok()?)
+}
+
+// parses OpenAI's reset durations such as "17ms", "1s" or "6m0s"
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0f64;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    let mut parsed_any = false;
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let value = number.parse::<f64>().ok()?;
+        number.clear();
+        let seconds = match c {
+            'h' => value * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                value / 1000.0
+            }
+            'm' => value * 60.0,
+            's' => value,
+            _ => return None,
+        };
+        total += seconds;
+        parsed_any = true;
+    }
+    if !number.is_empty() {
+        total += number.parse::<f64>().ok()?;
+        parsed_any = true;
+    }
+    if parsed_any { Some(Duration::from_secs_f64(total)) } else { None }
+}
+
+pub async fn acquire(model_name: &str, tokens: u64) {
+    loop {
+        let wait_time = match THROTTLE.lock() {
+            Ok(mut o) => { o.try_acquire(model_name, tokens) }
+            Err(_) => { None }
+        };
+        match wait_time {
+            None => return,
+            Some(wait_time) => {
+                println!("Throttle: waiting {:?} for '{}' capacity ({} tokens)", wait_time, model_name, tokens);
+                tokio::time::sleep(wait_time).await;
+            }
+        }
+    }
+}
+
+pub fn observe_response(model_name: &str, status: u16, headers: &HeaderMap) {
+    if let Ok(mut o) = THROTTLE.lock() {
+        o.observe_response(model_name, status, headers);
+    }
+}
+
+pub fn throttle_status() -> Vec<ModelThrottleStatus> {
+    match THROTTLE.lock() {
+        Ok(mut o) => { o.status() }
+        Err(_) => { Vec::new() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn parse_duration_formats() {
+        assert_eq!(parse_duration("17ms"), Some(Duration::from_millis(17)));
+        assert_eq!(parse_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_duration("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("1x"), None);
+    }
+
+    #[test]
+    fn token_bucket_refills_over_time() {
+        let mut bucket = TokenBucket::per_minute(60);
+        bucket.take(60.0);
+        assert!(bucket.wait_time(30.0) > Duration::from_secs(29));
+
+        bucket.last_refill = Instant::now() - Duration::from_secs(30);
+        assert_eq!(bucket.wait_time(30.0), Duration::ZERO);
+
+        // never refills beyond the capacity
+        bucket.last_refill = Instant::now() - Duration::from_secs(600);
+        bucket.refill();
+        assert_eq!(bucket.available, 60.0);
+    }
+
+    #[test]
+    fn default_limits_come_from_the_registry() {
+        assert_eq!(default_limits("gpt-4"), (200, 40_000));
+        assert_eq!(default_limits("gpt-4-0613"), (200, 40_000));
+        assert_eq!(default_limits("unknown-model"), (60, 40_000));
+    }
+
+    #[test]
+    fn learns_limits_from_headers() {
+        let mut throttle = Throttle::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-limit-requests", HeaderValue::from_static("500"));
+        headers.insert("x-ratelimit-limit-tokens", HeaderValue::from_static("30000"));
+        headers.insert("x-ratelimit-remaining-tokens", HeaderValue::from_static("0"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("6m0s"));
+        throttle.observe_response("gpt-4", 200, &headers);
+
+        let status = throttle.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].requests_per_minute, 500);
+        assert_eq!(status[0].tokens_per_minute, 30_000);
+        assert_eq!(status[0].available_tokens, 0);
+        assert!(status[0].learned_from_headers);
+        assert!(status[0].blocked_for_ms > 350_000);
+        assert!(throttle.try_acquire("gpt-4", 1).is_some());
+    }
+
+    #[test]
+    fn rate_limited_response_blocks_the_model() {
+        let mut throttle = Throttle::default();
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("20"));
+        throttle.observe_response("gpt-3.5-turbo", 429, &headers);
+        let wait_time = throttle.try_acquire("gpt-3.5-turbo", 1).unwrap();
+        assert!(wait_time > Duration::from_secs(19));
+        assert!(throttle.try_acquire("gpt-4", 1).is_none());
+    }
+}
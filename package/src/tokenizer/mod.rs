@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::api::estimate_tokens;
+use crate::models::MODEL_REGISTRY;
+
+// vocabulary files in the tiktoken format, e.g. ./tmp/tokenizers/cl100k_base.tiktoken
+pub const TOKENIZER_DIR: &str = "./tmp/tokenizers";
+
+pub const DEFAULT_CONTEXT_WINDOW: usize = 4_096;
+
+lazy_static!{
+   static ref TOKENIZERS: Mutex<HashMap<Encoding, Option<Arc<Tokenizer>>>> = Mutex::new(HashMap::new());
+
+   static ref CL100K_BASE_PATTERN: Regex = Regex::new(r"^(?:(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+)").unwrap();
+   static ref P50K_BASE_PATTERN: Regex = Regex::new(r"^(?:'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+)").unwrap();
+   static ref WHITESPACE_PATTERN: Regex = Regex::new(r"^\s+").unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Cl100kBase,
+    P50kBase,
+}
+
+impl Encoding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Cl100kBase => "cl100k_base",
+            Encoding::P50kBase => "p50k_base",
+        }
+    }
+
+    pub fn for_model(model_name: &str) -> Encoding {
+        if model_name.starts_with("gpt-4") || model_name.starts_with("gpt-3.5-turbo") || model_name.starts_with("text-embedding-") {
+            Encoding::Cl100kBase
+        } else {
+            Encoding::P50kBase
+        }
+    }
+
+    fn pattern(&self) -> &'static Regex {
+        match self {
+            Encoding::Cl100kBase => &CL100K_BASE_PATTERN,
+            Encoding::P50kBase => &P50K_BASE_PATTERN,
+        }
+    }
+
+    fn special_tokens(&self) -> Vec<(&'static str, u32)> {
+        match self {
+            Encoding::Cl100kBase => vec![
+                ("<|endoftext|>", 100257),
+                ("<|fim_prefix|>", 100258),
+                ("<|fim_middle|>", 100259),
+                ("<|fim_suffix|>", 100260),
+                ("<|endofprompt|>", 100276),
+            ],
+            Encoding::P50kBase => vec![("<|endoftext|>", 50256)],
+        }
+    }
+}
+
+pub struct Tokenizer {
+    encoding: Encoding,
+    ranks: HashMap<Vec<u8>, u32>,
+    decoder: HashMap<u32, Vec<u8>>,
+}
+
+impl Tokenizer {
+
+    pub fn load(encoding: Encoding, path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut ranks = HashMap::new();
+        for line in contents.lines().filter(|x| !x.trim().is_empty()) {
+            let mut parts = line.split_whitespace();
+            let (token, rank) = match (parts.next(), parts.next()) {
+                (Some(token), Some(rank)) => (token, rank),
+                _ => return Err(anyhow::anyhow!("Error: invalid vocabulary line '{}' in '{}'", line, path)),
+            };
+            let token = base64::engine::general_purpose::STANDARD.decode(token)?;
+            ranks.insert(token, rank.parse::<u32>()?);
+        }
+        let mut decoder: HashMap<u32, Vec<u8>> = ranks.iter().map(|(k, v)| (*v, k.to_owned())).collect();
+        for (token, rank) in encoding.special_tokens() {
+            decoder.insert(rank, token.as_bytes().to_vec());
+        }
+        Ok(Tokenizer { encoding, ranks, decoder })
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // special tokens are encoded as ordinary text
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut tokens = Vec::new();
+        for piece in split_pieces(self.encoding, text) {
+            tokens.append(&mut self.encode_piece(piece.as_bytes()));
+        }
+        tokens
+    }
+
+    pub fn decode(&self, tokens: &[u32]) -> String {
+        let bytes = tokens.iter()
+            .flat_map(|x| self.decoder.get(x).cloned().unwrap_or_default())
+            .collect::<Vec<u8>>();
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    pub fn count_tokens(&self, text: &str) -> usize {
+        self.encode(text).len()
+    }
+
+    pub fn truncate_to_tokens(&self, text: &str, max_tokens: usize) -> String {
+        let tokens = self.encode(text);
+        if tokens.len() <= max_tokens {
+            return text.to_string();
+        }
+        let byte_len: usize = tokens[..max_tokens].iter()
+            .map(|x| self.decoder.get(x).map(|x| x.len()).unwrap_or(0))
+            .sum();
+        text[..floor_char_boundary(text, byte_len)].to_string()
+    }
+
+    fn encode_piece(&self, piece: &[u8]) -> Vec<u32> {
+        if let Some(rank) = self.ranks.get(piece) {
+            return vec![*rank];
+        }
+        byte_pair_merge(piece, &self.ranks).windows(2)
+            .map(|x| self.ranks.get(&piece[x[0]..x[1]]).copied().unwrap_or(u32::MAX))
+            .collect()
+    }
+}
+
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+// splits the text the same way tiktoken's regex does, the `\s+(?!\S)` lookahead is handled by hand
+fn split_pieces(encoding: Encoding, text: &str) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut position = 0;
+    while position < text.len() {
+        let rest = &text[position..];
+        let mut len = encoding.pattern().find(rest).map(|x| x.end()).unwrap_or(0);
+        if len == 0 {
+            if let Some(whitespace) = WHITESPACE_PATTERN.find(rest) {
+                len = whitespace.end();
+                if len < rest.len() {
+                    // leave the last whitespace character to the following piece
+                    let last_char = rest[..len].chars().next_back().map(|x| x.len_utf8()).unwrap_or(0);
+                    if len > last_char {
+                        len -= last_char;
+                    }
+                }
+            } else {
+                len = rest.chars().next().map(|x| x.len_utf8()).unwrap_or(rest.len());
+            }
+        }
+        pieces.push(&rest[..len]);
+        position += len;
+    }
+    pieces
+}
+
+// returns the boundaries of the merged parts
+fn byte_pair_merge(piece: &[u8], ranks: &HashMap<Vec<u8>, u32>) -> Vec<usize> {
+    let rank_of = |parts: &Vec<(usize, u32)>, start: usize, skip: usize| -> u32 {
+        if start + skip + 2 < parts.len() {
+            ranks.get(&piece[parts[start].0..parts[start + skip + 2].0]).copied().unwrap_or(u32::MAX)
+        } else {
+            u32::MAX
+        }
+    };
+
+    let mut parts: Vec<(usize, u32)> = (0..piece.len() + 1).map(|i| (i, u32::MAX)).collect();
+    for i in 0..parts.len().saturating_sub(2) {
+        parts[i].1 = rank_of(&parts, i, 0);
+    }
+
+    while parts.len() > 1 {
+        let (i, min_rank) = parts[..parts.len() - 1].iter().enumerate()
+            .map(|(i, x)| (i, x.1))
+            .min_by_key(|x| x.1)
+            .unwrap();
+        if min_rank == u32::MAX {
+            break;
+        }
+        if i > 0 {
+            parts[i - 1].1 = rank_of(&parts, i - 1, 1);
+        }
+        parts[i].1 = rank_of(&parts, i, 1);
+        parts.remove(i + 1);
+    }
+    parts.into_iter().map(|x| x.0).collect()
+}
+
+// loads the vocabulary once, returns None if the file is not available
+pub fn get_tokenizer(encoding: Encoding) -> Option<Arc<Tokenizer>> {
+    let mut tokenizers = TOKENIZERS.lock().ok()?;
+    tokenizers.entry(encoding).or_insert_with(|| {
+        let path = format!("{}/{}.tiktoken", TOKENIZER_DIR, encoding.name());
+        match Tokenizer::load(encoding, &path) {
+            Ok(tokenizer) => Some(Arc::new(tokenizer)),
+            Err(err) => {
+                println!("Tokenizer: could not load '{}' ({}), falling back to estimates", path, err);
+                None
+            }
+        }
+    }).clone()
+}
+
+pub fn count_tokens(model_name: &str, text: &str) -> usize {
+    match get_tokenizer(Encoding::for_model(model_name)) {
+        Some(tokenizer) => tokenizer.count_tokens(text),
+        None => estimate_tokens(text) as usize,
+    }
+}
+
+pub fn truncate_to_tokens(model_name: &str, text: &str, max_tokens: usize) -> String {
+    match get_tokenizer(Encoding::for_model(model_name)) {
+        Some(tokenizer) => tokenizer.truncate_to_tokens(text, max_tokens),
+        None => text[..floor_char_boundary(text, max_tokens * 4)].to_string(),
+    }
+}
+
+// every message is wrapped as <|start|>{role}\n{content}<|end|>\n and the reply is primed with <|start|>assistant<|message|>
+pub fn count_chat_tokens(model_name: &str, messages: &[(&str, &str)]) -> usize {
+    messages.iter()
+        .map(|(role, content)| 3 + count_tokens(model_name, role) + count_tokens(model_name, content))
+        .sum::<usize>() + 3
+}
+
+pub fn context_window(model_name: &str) -> usize {
+    MODEL_REGISTRY.context_window(model_name).unwrap_or(DEFAULT_CONTEXT_WINDOW)
+}
+
+#[derive(Debug, Clone)]
+pub struct ContextWindowExceeded {
+    pub model_name: String,
+    pub prompt_tokens: usize,
+    pub context_window: usize,
+}
+
+impl std::fmt::Display for ContextWindowExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error: prompt exceeds the context window of '{}' ({} of {} tokens)", self.model_name, self.prompt_tokens, self.context_window)
+    }
+}
+
+impl std::error::Error for ContextWindowExceeded {}
+
+// the requested limit, capped by what is left of the context window after the prompt
+pub fn clamp_completion_tokens(model_name: &str, prompt_tokens: usize, completion_token_limit: u16) -> anyhow::Result<u16> {
+    let context_window = context_window(model_name);
+    if prompt_tokens >= context_window {
+        return Err(ContextWindowExceeded { model_name: model_name.to_string(), prompt_tokens, context_window }.into());
+    }
+    let max_output_tokens = MODEL_REGISTRY.get(model_name).and_then(|x| x.max_output_tokens).unwrap_or(usize::MAX);
+    Ok((context_window - prompt_tokens).min(max_output_tokens).min(completion_token_limit as usize) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a vocabulary with every single byte plus the given merges, ranked in order after the bytes
+    fn test_tokenizer(merges: &[&str]) -> Tokenizer {
+        let mut ranks: HashMap<Vec<u8>, u32> = (0..=255u8).map(|x| (vec![x], x as u32)).collect();
+        for (i, merge) in merges.iter().enumerate() {
+            ranks.insert(merge.as_bytes().to_vec(), 256 + i as u32);
+        }
+        let decoder = ranks.iter().map(|(k, v)| (*v, k.to_owned())).collect();
+        Tokenizer { encoding: Encoding::Cl100kBase, ranks, decoder }
+    }
+
+    #[test]
+    fn split_pieces_matches_tiktoken_pre_tokenization() {
+        let cases: [(&str, &[&str]); 9] = [
+            ("hello world", &["hello", " world"]),
+            ("hello   world", &["hello", "  ", " world"]),
+            ("hello \n\nworld", &["hello", " \n\n", "world"]),
+            ("trailing  ", &["trailing", "  "]),
+            ("x\t\ty", &["x", "\t", "\ty"]),
+            ("2 + 2 = 4", &["2", " +", " ", "2", " =", " ", "4"]),
+            ("12345", &["123", "45"]),
+            ("I'm here", &["I", "'m", " here"]),
+            ("héllo wörld お誕生日!!!\n", &["héllo", " wörld", " お誕生日", "!!!\n"]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(split_pieces(Encoding::Cl100kBase, text), expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn byte_pair_merge_takes_the_lowest_rank_first() {
+        // "bc" outranks "ab", so "a" + "bc" is merged instead of "ab" + "c"
+        let tokenizer = test_tokenizer(&["bc", "ab"]);
+        assert_eq!(byte_pair_merge(b"abcd", &tokenizer.ranks), vec![0, 1, 3, 4]);
+        assert_eq!(tokenizer.encode("abcd"), vec![b'a' as u32, 256, b'd' as u32]);
+        let tokenizer = test_tokenizer(&["ab", "bc"]);
+        assert_eq!(tokenizer.encode("abcd"), vec![256, b'c' as u32, b'd' as u32]);
+        // merged parts keep merging as long as the result is in the vocabulary
+        let tokenizer = test_tokenizer(&["bc", "ab", "abc", "abcd"]);
+        assert_eq!(byte_pair_merge(b"abcd", &tokenizer.ranks), vec![0, 4]);
+        assert_eq!(tokenizer.encode("abcde"), vec![259, b'e' as u32]);
+    }
+
+    #[test]
+    fn encode_and_decode_round_trip_non_ascii() {
+        let tokenizer = test_tokenizer(&["ö", " w", " wö"]);
+        let text = "héllo wörld  お誕生日";
+        let tokens = tokenizer.encode(text);
+        assert_eq!(tokenizer.decode(&tokens), text);
+        assert!(tokens.contains(&258));
+        assert_eq!(tokenizer.truncate_to_tokens(text, 2), "h");
+    }
+
+    #[test]
+    #[ignore = "needs cl100k_base.tiktoken in TOKENIZER_DIR, run with --ignored"]
+    fn cl100k_base_counts_match_tiktoken() {
+        let tokenizer = Tokenizer::load(Encoding::Cl100kBase, &format!("{}/cl100k_base.tiktoken", TOKENIZER_DIR)).unwrap();
+        assert_eq!(tokenizer.encode("tiktoken is great!"), vec![83, 1609, 5963, 374, 2294, 0]);
+        assert_eq!(tokenizer.encode("2 + 2 = 4"), vec![17, 489, 220, 17, 284, 220, 19]);
+        let counts = [
+            ("hello world", 2),
+            ("Hello, world!", 4),
+            ("hello   world", 3),
+            ("antidisestablishmentarianism", 6),
+            ("お誕生日おめでとう", 9),
+        ];
+        for (text, count) in counts {
+            assert_eq!(tokenizer.count_tokens(text), count, "{:?}", text);
+        }
+    }
+}
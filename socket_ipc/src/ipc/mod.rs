@@ -8,25 +8,61 @@ use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
 pub fn client_send_openai_gpt_chat_completion_request(socket_path: &str, model_name: String, system: String, prompt: String, completion_token_limit: u16) -> anyhow::Result<OpenAIGPTResult> {
-    println!("Initiating OpenAI GPT chat completion request for (model, system, prompt): '{:?}'",  (&model_name, &system[..50], &prompt[..50]));
-    client_send_request(socket_path, OpenAIGPTRequest::ChatCompletionRequest(OpenAIGPTChatCompletionRequest {model_name, system,prompt,completion_token_limit}))
+    println!("Initiating OpenAI GPT chat completion request for (model, system, prompt): '{:?}'",  (&model_name, system.chars().take(50).collect::<String>(), prompt.chars().take(50).collect::<String>()));
+    client_send_request(socket_path, OpenAIGPTRequest::ChatCompletionRequest(OpenAIGPTChatCompletionRequest {model_name, system,prompt,completion_token_limit, routing: None, moderation: None, injection: None, stop: None}))
+}
+
+pub fn client_send_openai_gpt_routed_chat_completion_request(socket_path: &str, routing: OpenAIGPTRouting, system: String, prompt: String, completion_token_limit: u16) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT chat completion request for (routing, system, prompt): '{:?}'",  (&routing, system.chars().take(50).collect::<String>(), prompt.chars().take(50).collect::<String>()));
+    let model_name = routing.models.first().cloned().unwrap_or_default();
+    client_send_request(socket_path, OpenAIGPTRequest::ChatCompletionRequest(OpenAIGPTChatCompletionRequest {model_name, system,prompt,completion_token_limit, routing: Some(routing), moderation: None, injection: None, stop: None}))
 }
 
 pub fn client_send_openai_gpt_text_completion_request(socket_path: &str, prompt: String, completion_token_limit: u16) -> anyhow::Result<OpenAIGPTResult> {
-    println!("Initiating OpenAI GPT text completion request for prompt: '{}'",  &prompt[..50]);
-    client_send_request(socket_path, OpenAIGPTRequest::TextCompletionRequest(OpenAIGPTTextCompletionRequest {prompt,completion_token_limit}))
+    println!("Initiating OpenAI GPT text completion request for prompt: '{}'",  prompt.chars().take(50).collect::<String>());
+    client_send_request(socket_path, OpenAIGPTRequest::TextCompletionRequest(OpenAIGPTTextCompletionRequest {model_name: None, prompt,completion_token_limit, moderation: None, injection: None, stop: None}))
 }
 
 pub fn client_send_openai_gpt_embedding_request(socket_path: &str, texts: Vec<String>) -> anyhow::Result<OpenAIGPTResult> {
     println!("Initiating OpenAI GPT embedding request for {} texts", texts.len());
-    client_send_request(socket_path, OpenAIGPTRequest::EmbeddingRequest(OpenAIGPTEmbeddingRequest {texts}))
+    client_send_request(socket_path, OpenAIGPTRequest::EmbeddingRequest(OpenAIGPTEmbeddingRequest {texts, model_name: None, dimensions: None, encoding_format: None}))
+}
+
+pub fn client_send_openai_gpt_model_embedding_request(socket_path: &str, model_name: String, dimensions: Option<u32>, texts: Vec<String>) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT embedding request for {} texts with (model, dimensions): '{:?}'", texts.len(), (&model_name, &dimensions));
+    client_send_request(socket_path, OpenAIGPTRequest::EmbeddingRequest(OpenAIGPTEmbeddingRequest {texts, model_name: Some(model_name), dimensions, encoding_format: Some(OpenAIGPTEmbeddingEncoding::Base64)}))
+}
+
+pub fn client_send_openai_gpt_fraud_classification_request(socket_path: &str, text: String) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT fraud classification request for text: '{}'", text.chars().take(50).collect::<String>());
+    client_send_request(socket_path, OpenAIGPTRequest::FraudClassificationRequest(OpenAIGPTFraudClassificationRequest {text, model_name: None}))
+}
+
+pub fn client_send_openai_gpt_chat_session_request(socket_path: &str, session_id: String, message: String) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT chat session request for (session, message): '{:?}'", (&session_id, message.chars().take(50).collect::<String>()));
+    client_send_request(socket_path, OpenAIGPTRequest::ChatSessionRequest(OpenAIGPTChatSessionRequest {session_id, message}))
+}
+
+pub fn client_send_openai_gpt_throttle_status_request(socket_path: &str) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT throttle status request");
+    client_send_request(socket_path, OpenAIGPTRequest::ThrottleStatusRequest)
+}
+
+pub fn client_send_openai_gpt_request_as_client(socket_path: &str, client_id: String, project: Option<String>, request: OpenAIGPTRequest) -> anyhow::Result<OpenAIGPTResult> {
+    println!("Initiating OpenAI GPT request as (client, project): '{:?}'", (&client_id, &project));
+    client_send_request(socket_path, OpenAIGPTRequest::ClientRequest(OpenAIGPTClientRequest {client_id, project, request: Box::new(request)}))
 }
 
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub enum OpenAIGPTRequest {
     ChatCompletionRequest(OpenAIGPTChatCompletionRequest),
     TextCompletionRequest(OpenAIGPTTextCompletionRequest),
-    EmbeddingRequest(OpenAIGPTEmbeddingRequest)
+    EmbeddingRequest(OpenAIGPTEmbeddingRequest),
+    ClientRequest(OpenAIGPTClientRequest),
+    FraudClassificationRequest(OpenAIGPTFraudClassificationRequest),
+    ChatSessionRequest(OpenAIGPTChatSessionRequest),
+    // the service's per-model rate limits, for monitoring
+    ThrottleStatusRequest
 }
 impl OpenAIGPTRequest {
     pub fn get_hash(&self) -> u64 {
@@ -50,30 +86,195 @@ impl TryFrom<OpenAIGPTRequest> for Vec<u8> {
     }
 }
 
+// the service only accepts a client id that its budget config maps to the sender's uid
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTClientRequest {
+    pub client_id: String,
+    pub project: Option<String>,
+    pub request: Box<OpenAIGPTRequest>,
+}
+
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub struct OpenAIGPTChatCompletionRequest {
     pub model_name: String,
     pub system: String,
     pub prompt: String,
     pub completion_token_limit: u16,
+    pub routing: Option<OpenAIGPTRouting>,
+    // None uses the service's default, the service may raise a mode the client is not allowed to lower
+    pub moderation: Option<OpenAIGPTModerationMode>,
+    // None uses the service's default
+    pub injection: Option<OpenAIGPTInjectionMode>,
+    // None uses the service's default stop tokens, OpenAI accepts at most 4
+    pub stop: Option<Vec<String>>,
+}
+
+// how the untrusted text in each <source>...</source> pair of the prompt is treated
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq)]
+pub enum OpenAIGPTInjectionMode {
+    Off,
+    // only reports the risk, the prompt is sent as it is
+    Report,
+    // escapes tags, removes known injection phrases and wraps the source in randomized delimiters
+    Neutralize,
+    // neutralizes and asks a classifier prompt for the risk
+    Classify,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct OpenAIGPTInjectionReport {
+    // 0.0 to 1.0, the higher of the heuristic and the classifier score
+    pub score: f32,
+    pub classifier_score: Option<f32>,
+    // the tag sequences and injection phrases found in the source
+    pub markers: Vec<String>,
+    pub neutralized: bool,
+}
+
+impl Hash for OpenAIGPTInjectionReport {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.score.to_bits().hash(state);
+        self.classifier_score.map(|x| x.to_bits()).hash(state);
+        self.markers.hash(state);
+        self.neutralized.hash(state);
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq)]
+pub enum OpenAIGPTModerationMode {
+    Off,
+    InputOnly,
+    OutputOnly,
+    Both,
+}
+
+impl OpenAIGPTModerationMode {
+    pub fn from_flags(input: bool, output: bool) -> Self {
+        match (input, output) {
+            (true, true) => OpenAIGPTModerationMode::Both,
+            (true, false) => OpenAIGPTModerationMode::InputOnly,
+            (false, true) => OpenAIGPTModerationMode::OutputOnly,
+            (false, false) => OpenAIGPTModerationMode::Off,
+        }
+    }
+
+    pub fn moderates_input(&self) -> bool {
+        matches!(self, OpenAIGPTModerationMode::InputOnly | OpenAIGPTModerationMode::Both)
+    }
+
+    pub fn moderates_output(&self) -> bool {
+        matches!(self, OpenAIGPTModerationMode::OutputOnly | OpenAIGPTModerationMode::Both)
+    }
+
+    // the mode that moderates everything either of the two modes moderates
+    pub fn union(&self, other: OpenAIGPTModerationMode) -> Self {
+        OpenAIGPTModerationMode::from_flags(self.moderates_input() || other.moderates_input(), self.moderates_output() || other.moderates_output())
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq)]
+pub enum OpenAIGPTRoutingPolicy {
+    CheapestFirst,
+    QualityFirst,
+    // quality first while the budget lasts, cheapest first once it runs low
+    BudgetAware,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq)]
+pub enum OpenAIGPTErrorClass {
+    RateLimited,
+    Overloaded,
+    ServerError,
+    Timeout,
+    ContextLengthExceeded,
+    BudgetExceeded,
+    ModelUnavailable,
+    Other,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTRouting {
+    // tried in order, unless a policy reorders them, empty means the service's default candidates
+    pub models: Vec<String>,
+    pub policy: Option<OpenAIGPTRoutingPolicy>,
+    pub fallback_on: Vec<OpenAIGPTErrorClass>,
+}
+
+impl OpenAIGPTRouting {
+    pub fn models(models: Vec<String>) -> Self {
+        OpenAIGPTRouting { models, policy: None, fallback_on: OpenAIGPTRouting::default_fallback_on() }
+    }
+
+    pub fn policy(policy: OpenAIGPTRoutingPolicy) -> Self {
+        OpenAIGPTRouting { models: Vec::new(), policy: Some(policy), fallback_on: OpenAIGPTRouting::default_fallback_on() }
+    }
+
+    pub fn default_fallback_on() -> Vec<OpenAIGPTErrorClass> {
+        vec![
+            OpenAIGPTErrorClass::RateLimited,
+            OpenAIGPTErrorClass::Overloaded,
+            OpenAIGPTErrorClass::ServerError,
+            OpenAIGPTErrorClass::Timeout,
+            OpenAIGPTErrorClass::BudgetExceeded,
+        ]
+    }
 }
 
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub struct OpenAIGPTTextCompletionRequest {
+    // None uses the service's default text completion model
+    pub model_name: Option<String>,
     pub prompt: String,
     pub completion_token_limit: u16,
+    pub moderation: Option<OpenAIGPTModerationMode>,
+    pub injection: Option<OpenAIGPTInjectionMode>,
+    // None uses the service's default stop tokens, OpenAI accepts at most 4
+    pub stop: Option<Vec<String>>,
 }
 
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub struct OpenAIGPTEmbeddingRequest {
-    pub texts: Vec<String>
+    pub texts: Vec<String>,
+    // None uses the service's default embedding model
+    pub model_name: Option<String>,
+    // shortens the embeddings, only supported by the text-embedding-3 models
+    pub dimensions: Option<u32>,
+    // how OpenAI sends the embeddings to the service, None is Float
+    pub encoding_format: Option<OpenAIGPTEmbeddingEncoding>,
+}
+
+// one message of a conversation the service keeps the history of, never answered from the cache
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTChatSessionRequest {
+    // sessions are separate per client
+    pub session_id: String,
+    pub message: String,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTFraudClassificationRequest {
+    pub text: String,
+    // None uses the service's fraud detection model
+    pub model_name: Option<String>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq,Default)]
+pub enum OpenAIGPTEmbeddingEncoding {
+    #[default]
+    Float,
+    // little-endian f32s, about a quarter of the size of the JSON numbers
+    Base64,
 }
 
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub enum OpenAIGPTResult {
     ChatCompletionResult(OpenAIGPTChatCompletionResult),
     TextCompletionResult(OpenAIGPTTextCompletionResult),
-    EmbeddingResult(OpenAIGPTEmbeddingResult)
+    EmbeddingResult(OpenAIGPTEmbeddingResult),
+    ModerationRejectionResult(OpenAIGPTModerationRejection),
+    FraudClassificationResult(OpenAIGPTFraudClassificationResult),
+    ChatSessionResult(OpenAIGPTChatSessionResult),
+    ThrottleStatusResult(OpenAIGPTThrottleStatusResult)
 }
 
 impl TryFrom<Vec<u8>> for OpenAIGPTResult {
@@ -110,12 +311,42 @@ impl TryFrom<OpenAIGPTResult> for Vec<u8> {
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub struct OpenAIGPTChatCompletionResult {
     pub result: String,
+    pub model_name: String,
+    // None if the request's injection mode is Off
+    pub injection: Option<OpenAIGPTInjectionReport>,
     pub request: OpenAIGPTChatCompletionRequest,
 }
 
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTChatSessionResult {
+    pub result: String,
+    pub model_name: String,
+    // the turns kept in full, older ones are summarized or dropped
+    pub turns: u32,
+    pub request: OpenAIGPTChatSessionRequest,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTModelThrottleStatus {
+    pub model_name: String,
+    pub requests_per_minute: u64,
+    pub tokens_per_minute: u64,
+    pub available_requests: u64,
+    pub available_tokens: u64,
+    // the limits come from OpenAI's rate limit headers instead of the defaults
+    pub learned_from_headers: bool,
+    pub blocked_for_ms: u64,
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone)]
+pub struct OpenAIGPTThrottleStatusResult {
+    pub models: Vec<OpenAIGPTModelThrottleStatus>,
+}
+
 #[derive(Serialize,Deserialize,Debug,Hash,Clone)]
 pub struct OpenAIGPTTextCompletionResult {
     pub result: String,
+    pub injection: Option<OpenAIGPTInjectionReport>,
     pub request: OpenAIGPTTextCompletionRequest,
 }
 
@@ -128,6 +359,59 @@ pub struct OpenAIGPTEmbeddingResult {
 impl Hash for OpenAIGPTEmbeddingResult {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.request.hash(state);
-        state.finish();
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum OpenAIGPTFraudLabel {
+    Legitimate,
+    Suspicious,
+    Fraud,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct OpenAIGPTFraudClassificationResult {
+    pub label: OpenAIGPTFraudLabel,
+    // 0.0 to 1.0, how sure the service is about the label
+    pub confidence: f32,
+    pub reasons: Vec<String>,
+    pub offending_urls: Vec<String>,
+    pub model_name: String,
+    pub request: OpenAIGPTFraudClassificationRequest,
+}
+
+impl Hash for OpenAIGPTFraudClassificationResult {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.request.hash(state);
+    }
+}
+
+#[derive(Serialize,Deserialize,Debug,Hash,Clone,Copy,PartialEq,Eq)]
+pub enum OpenAIGPTModerationSide {
+    Prompt,
+    Result,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct OpenAIGPTModerationCategory {
+    pub category: String,
+    pub score: f32,
+    // None if the category was rejected because OpenAI flagged it
+    pub threshold: Option<f32>,
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct OpenAIGPTModerationRejection {
+    pub side: OpenAIGPTModerationSide,
+    pub categories: Vec<OpenAIGPTModerationCategory>,
+    // the rejected completion with the offending sentences masked, only if the service is configured to return it
+    pub redacted_result: Option<String>,
+    pub request: OpenAIGPTRequest,
+}
+
+impl Hash for OpenAIGPTModerationRejection {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.side.hash(state);
+        self.request.hash(state);
     }
 }
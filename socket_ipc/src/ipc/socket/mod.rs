@@ -5,46 +5,51 @@ use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 use core::future::Future;
 
+#[derive(Serialize,Deserialize,Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct PeerCredentials {
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
 
 pub fn spawn_socket_service<F,T>(socket_path: &str, handler: F) -> JoinHandle<()>
     where
-        F: Fn(Vec<u8>) -> T + Send + Sync + 'static,
+        F: Fn(Vec<u8>, Option<PeerCredentials>) -> T + Send + Sync + 'static,
         T: Future<Output = anyhow::Result<Vec<u8>>> + Send
     {
     let socket_path = socket_path.to_owned();
     tokio::task::spawn(async move {
-        loop {
-            if std::fs::metadata(&socket_path).is_ok() {
-                //println!("A socket is already present. Deleting...");
-                std::fs::remove_file(&socket_path)
-                    .with_context(|| {
-                        format!("could not delete previous socket at {:?}", &socket_path)
-                    })
-                    .unwrap();
-            }
-
-            let unix_listener = UnixListener::bind(&socket_path)
-                .context("Could not create the unix socket")
+        if std::fs::metadata(&socket_path).is_ok() {
+            //println!("A socket is already present. Deleting...");
+            std::fs::remove_file(&socket_path)
+                .with_context(|| {
+                    format!("could not delete previous socket at {:?}", &socket_path)
+                })
                 .unwrap();
+        }
 
-            loop {
-                let (unix_stream, _socket_address) = unix_listener
-                    .accept()
-                    .context("Failed at accepting a connection on the unix listener")
-                    .unwrap();
+        let unix_listener = UnixListener::bind(&socket_path)
+            .context("Could not create the unix socket")
+            .unwrap();
 
-                handle_stream(unix_stream, &handler).await.ok();
-            }
+        loop {
+            let (unix_stream, _socket_address) = unix_listener
+                .accept()
+                .context("Failed at accepting a connection on the unix listener")
+                .unwrap();
+
+            handle_stream(unix_stream, &handler).await.ok();
         }
     })
 }
 
 async fn handle_stream<F,T>(mut unix_stream: UnixStream, handler: F) -> anyhow::Result<()>
     where
-        F: Fn(Vec<u8>) -> T + Send + Sync,
+        F: Fn(Vec<u8>, Option<PeerCredentials>) -> T + Send + Sync,
         T: Future<Output = anyhow::Result<Vec<u8>>>
 {
-    let encoded: Vec<u8> = handler(get_bytes_from_stream(&mut unix_stream)?).await?;
+    let peer_credentials = get_peer_credentials(&unix_stream);
+    let encoded: Vec<u8> = handler(get_bytes_from_stream(&mut unix_stream)?, peer_credentials).await?;
 
     unix_stream
         .write(&encoded[..])
@@ -66,7 +71,7 @@ pub fn client_send_request<T,S>(
     let mut unix_stream = UnixStream::connect(socket_path).context("Could not create stream")?;
 
     write_request_and_shutdown(&mut unix_stream, request.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))?)?;
-    Ok(get_bytes_from_stream(&mut unix_stream)?.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))?)
+    get_bytes_from_stream(&mut unix_stream)?.try_into().map_err(|_| anyhow::anyhow!("try_into() failed"))
 }
 
 fn write_request_and_shutdown(
@@ -89,4 +94,44 @@ fn get_bytes_from_stream(unix_stream: &mut UnixStream) -> anyhow::Result<Vec<u8>
         .read_to_end(&mut bytes)
         .context("Failed at reading the unix stream")?;
     Ok(bytes)
-}
\ No newline at end of file
+}
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_peer_credentials(unix_stream: &UnixStream) -> Option<PeerCredentials> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut ucred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            unix_stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == 0 && len as usize == std::mem::size_of::<libc::ucred>() {
+        Some(PeerCredentials { pid: Some(ucred.pid), uid: ucred.uid, gid: ucred.gid })
+    } else {
+        None
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+fn get_peer_credentials(unix_stream: &UnixStream) -> Option<PeerCredentials> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    let ret = unsafe { libc::getpeereid(unix_stream.as_raw_fd(), &mut uid, &mut gid) };
+    if ret == 0 {
+        Some(PeerCredentials { pid: None, uid, gid })
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly")))]
+fn get_peer_credentials(_unix_stream: &UnixStream) -> Option<PeerCredentials> {
+    None
+}